@@ -0,0 +1,138 @@
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Value;
+use crate::value::sigmoid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Activation {
+    Identity,
+    Relu,
+    /// `max(x, 0) + slope * min(x, 0)`.
+    LeakyRelu(f64),
+    /// `x` for positive `x`, `alpha * (e^x - 1)` otherwise.
+    Elu(f64),
+    /// Tanh approximation of GELU.
+    Gelu,
+    /// `x * sigmoid(x)`, also known as swish.
+    Silu,
+    Tanh,
+    Sigmoid,
+    Softplus,
+}
+
+const GELU_C: f64 = 0.044715;
+
+// Same operation order as the graph in `apply` so both give identical results.
+fn gelu_inner(x: f64) -> f64 {
+    (x + x.powi(3) * GELU_C) * (2.0 / PI).sqrt()
+}
+
+impl Activation {
+    pub fn apply(&self, x: &Value) -> Value {
+        match self {
+            Activation::Identity => x.clone(),
+            Activation::Relu => x.relu(),
+            Activation::LeakyRelu(slope) => x.relu() - x.neg().relu() * *slope,
+            Activation::Elu(alpha) => x.relu() + (x.min(&Value::new(0.0)).exp() - 1.0) * *alpha,
+            Activation::Gelu => {
+                let inner = (x + x.powi(3) * GELU_C) * (2.0 / PI).sqrt();
+                x * (inner.tanh() + 1.0) * 0.5
+            },
+            Activation::Silu => x * x.sigmoid(),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => x.sigmoid(),
+            Activation::Softplus => x.softplus(),
+        }
+    }
+
+    pub fn apply_f64(&self, x: f64) -> f64 {
+        match self {
+            Activation::Identity => x,
+            Activation::Relu => x.max(0.0),
+            Activation::LeakyRelu(slope) => if x > 0.0 { x } else { slope * x },
+            Activation::Elu(alpha) => if x > 0.0 { x } else { alpha * (x.exp() - 1.0) },
+            Activation::Gelu => x * (gelu_inner(x).tanh() + 1.0) * 0.5,
+            Activation::Silu => x * sigmoid(x),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => sigmoid(x),
+            Activation::Softplus => x.max(0.0) + (-x.abs()).exp().ln_1p(),
+        }
+    }
+
+    /// Derivative with respect to the input `x`.
+    pub fn derivative_f64(&self, x: f64) -> f64 {
+        match self {
+            Activation::Identity => 1.0,
+            Activation::Relu => if x > 0.0 { 1.0 } else { 0.0 },
+            Activation::LeakyRelu(slope) => if x > 0.0 { 1.0 } else { *slope },
+            Activation::Elu(alpha) => if x > 0.0 { 1.0 } else { alpha * x.exp() },
+            Activation::Gelu => {
+                let t = gelu_inner(x).tanh();
+                let d_inner = (2.0 / PI).sqrt() * (1.0 + 3.0 * GELU_C * x * x);
+                0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * d_inner
+            },
+            Activation::Silu => {
+                let s = sigmoid(x);
+                s * (1.0 + x * (1.0 - s))
+            },
+            Activation::Tanh => 1.0 - x.tanh().powi(2),
+            Activation::Sigmoid => {
+                let s = sigmoid(x);
+                s * (1.0 - s)
+            },
+            Activation::Softplus => sigmoid(x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck;
+
+    const ALL: [Activation; 9] = [
+        Activation::Identity,
+        Activation::Relu,
+        Activation::LeakyRelu(0.1),
+        Activation::Elu(1.0),
+        Activation::Gelu,
+        Activation::Silu,
+        Activation::Tanh,
+        Activation::Sigmoid,
+        Activation::Softplus,
+    ];
+
+    #[test]
+    fn test_value_and_f64_agree() {
+        for activation in ALL {
+            for x in [-3.0, -0.5, 0.4, 2.0] {
+                let v = Value::new(x);
+                let out = activation.apply(&v);
+                out.backward();
+
+                assert!((out.data() - activation.apply_f64(x)).abs() < 1e-12, "{:?} at {}", activation, x);
+                assert!((v.grad() - activation.derivative_f64(x)).abs() < 1e-9, "{:?} grad at {}", activation, x);
+            }
+        }
+    }
+
+    #[test]
+    fn test_grads() {
+        for activation in ALL {
+            gradcheck(&[-1.3, -0.2, 0.7, 2.2], |x| {
+                x.iter().map(|v| activation.apply(v)).sum::<Value>()
+            }).assert_ok();
+        }
+    }
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(Activation::LeakyRelu(0.01).apply_f64(-2.0), -0.02);
+        assert!((Activation::Elu(1.0).apply_f64(-1.0) - (1.0f64.exp().recip() - 1.0)).abs() < 1e-12);
+        assert!((Activation::Gelu.apply_f64(1.0) - 0.841192).abs() < 1e-6);
+        assert!((Activation::Silu.apply_f64(1.0) - 0.731059).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::Value;
+use crate::value::GraphNode;
+
+/// Controls how much of a graph `Value::to_dot_with` draws.
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    max_nodes: Option<usize>,
+    max_depth: Option<usize>,
+    collapse: bool,
+}
+
+impl DotOptions {
+    pub fn new() -> DotOptions {
+        DotOptions::default()
+    }
+
+    /// Stops after this many nodes, closest to the output first.
+    pub fn max_nodes(mut self, max_nodes: usize) -> DotOptions {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    /// Only draws nodes at most this many ops away from the output.
+    pub fn max_depth(mut self, max_depth: usize) -> DotOptions {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Merges chains of the same op, like the adds of a `sum`, into one node
+    /// and draws the leaves feeding a node as a single count.
+    pub fn collapse(mut self, collapse: bool) -> DotOptions {
+        self.collapse = collapse;
+        self
+    }
+}
+
+struct Group<'a> {
+    node: &'a GraphNode,
+    size: usize,
+    inputs: Vec<usize>,
+}
+
+impl Value {
+    /// The graph that produced this value as a Graphviz DOT document, e.g.
+    /// for `dot -Tsvg`. Each node shows its op, data and grad.
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(&DotOptions::default())
+    }
+
+    pub fn to_dot_with(&self, options: &DotOptions) -> String {
+        let nodes = self.graph_nodes(options.max_depth, options.max_nodes);
+        let index = nodes.iter()
+            .enumerate()
+            .map(|(i, n)| (n.id, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut consumers = HashMap::new();
+        for node in &nodes {
+            for parent in &node.parents {
+                *consumers.entry(*parent).or_insert(0) += 1;
+            }
+        }
+
+        // Nodes in breadth first order, so a chain is always entered at its
+        // output and absorbs the same op parents only it consumes.
+        let mut owner: HashMap<usize, usize> = HashMap::new();
+        let mut groups: Vec<Group> = vec![];
+        for node in &nodes {
+            if owner.contains_key(&node.id) {
+                continue;
+            }
+            owner.insert(node.id, node.id);
+            let mut group = Group { node, size: 1, inputs: vec![] };
+
+            let mut stack = node.parents.clone();
+            stack.reverse();
+            while let Some(parent) = stack.pop() {
+                let absorb = options.collapse
+                    && node.op.is_some()
+                    && consumers[&parent] == 1
+                    && index.get(&parent).is_some_and(|&i| nodes[i].op == node.op)
+                    && !owner.contains_key(&parent);
+                if absorb {
+                    owner.insert(parent, node.id);
+                    group.size += 1;
+                    stack.extend(nodes[index[&parent]].parents.iter().rev());
+                } else {
+                    group.inputs.push(parent);
+                }
+            }
+            groups.push(group);
+        }
+
+        let is_leaf = |id: &usize| index.get(id).is_some_and(|&i| nodes[i].op.is_none());
+
+        let mut out = String::new();
+        writeln!(out, "digraph G {{").unwrap();
+        writeln!(out, "  rankdir=LR;").unwrap();
+        writeln!(out, "  node [shape=record];").unwrap();
+
+        for group in &groups {
+            let node = group.node;
+            if options.collapse && node.op.is_none() && consumers.get(&node.id).is_some_and(|&c| c > 0) {
+                // Drawn as part of the leaf count of its consumers.
+                continue;
+            }
+
+            let op = match (&node.op_label, group.size) {
+                (Some(label), 1) => format!("{} | ", label),
+                (Some(_), size) => format!("{} x{} | ", node.op.unwrap(), size),
+                (None, _) => String::new(),
+            };
+            writeln!(out, "  n{} [label=\"{{ {}data {:.4} | grad {:.4} }}\"];", node.id, op, node.data, node.grad).unwrap();
+
+            let mut hidden = 0;
+            let mut leaves = 0;
+            for input in &group.inputs {
+                if !index.contains_key(input) {
+                    hidden += 1;
+                } else if options.collapse && is_leaf(input) {
+                    leaves += 1;
+                } else {
+                    writeln!(out, "  n{} -> n{};", owner[input], node.id).unwrap();
+                }
+            }
+            if leaves > 0 {
+                let noun = if leaves == 1 { "leaf" } else { "leaves" };
+                writeln!(out, "  n{}_leaves [label=\"{} {}\", shape=box, style=rounded];", node.id, leaves, noun).unwrap();
+                writeln!(out, "  n{}_leaves -> n{};", node.id, node.id).unwrap();
+            }
+            if hidden > 0 {
+                writeln!(out, "  n{}_more [label=\"{} more\", shape=plaintext];", node.id, hidden).unwrap();
+                writeln!(out, "  n{}_more -> n{} [style=dashed];", node.id, node.id).unwrap();
+            }
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(dot: &str, pattern: &str) -> usize {
+        dot.matches(pattern).count()
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let a = Value::new(2.0);
+        let b = Value::new(-3.0);
+        let c = &a * &b + 1.0;
+        c.backward();
+
+        let dot = c.to_dot();
+        assert!(dot.starts_with("digraph G {"));
+        assert!(dot.contains("{ Add | data -5.0000 | grad 1.0000 }"));
+        assert!(dot.contains("{ Mul | data -6.0000 | grad 1.0000 }"));
+        assert!(dot.contains("{ data 2.0000 | grad -3.0000 }"));
+        // a, b, the constant 1, the product and the sum.
+        assert_eq!(count(&dot, "[label="), 5);
+        assert_eq!(count(&dot, " -> "), 4);
+    }
+
+    #[test]
+    fn test_depth_and_node_limits() {
+        let x = Value::new(0.5);
+        let y = x.exp().tanh().sigmoid().log();
+
+        let dot = y.to_dot_with(&DotOptions::new().max_depth(1));
+        assert_eq!(count(&dot, "[label=\"{"), 2);
+        assert!(dot.contains("1 more"));
+
+        let dot = y.to_dot_with(&DotOptions::new().max_nodes(3));
+        assert_eq!(count(&dot, "[label=\"{"), 3);
+    }
+
+    #[test]
+    fn test_collapse() {
+        let inputs = (0..10).map(|i| Value::new(i as f64)).collect::<Vec<_>>();
+        let weights = (0..10).map(|_| Value::new(0.5)).collect::<Vec<_>>();
+        let out = inputs.iter().zip(weights.iter()).map(|(x, w)| x * w).sum::<Value>();
+
+        let full = out.to_dot();
+        let collapsed = out.to_dot_with(&DotOptions::new().collapse(true));
+        assert_eq!(count(&full, "[label=\"{"), 41);
+        assert_eq!(count(&collapsed, "[label=\"{"), 11);
+        // The 10 adds of the sum become one node, the products keep their
+        // two leaves as a count.
+        assert!(collapsed.contains("Add x10"));
+        assert_eq!(count(&collapsed, "Mul |"), 10);
+        assert_eq!(count(&collapsed, "\"2 leaves\""), 10);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+
+use crate::Value;
+
+#[derive(Debug, Clone)]
+pub struct GradcheckConfig {
+    /// Step used for the central finite difference.
+    pub epsilon: f64,
+    /// Absolute tolerance.
+    pub atol: f64,
+    /// Tolerance relative to the magnitude of the numeric gradient.
+    pub rtol: f64,
+}
+
+impl Default for GradcheckConfig {
+    fn default() -> Self {
+        GradcheckConfig {
+            epsilon: 1e-6,
+            atol: 1e-5,
+            rtol: 1e-4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GradMismatch {
+    pub input: usize,
+    pub analytic: f64,
+    pub numeric: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct GradcheckReport {
+    pub output: f64,
+    pub analytic: Vec<f64>,
+    pub numeric: Vec<f64>,
+    pub mismatches: Vec<GradMismatch>,
+}
+
+impl GradcheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Panics with the report if any gradient did not match.
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "{}", self);
+    }
+}
+
+impl fmt::Display for GradcheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "gradcheck ok for {} inputs", self.analytic.len());
+        }
+
+        writeln!(f, "gradcheck failed for {} of {} inputs:", self.mismatches.len(), self.analytic.len())?;
+        for m in &self.mismatches {
+            writeln!(f, "  input {}: analytic {} numeric {} diff {}", m.input, m.analytic, m.numeric, (m.analytic - m.numeric).abs())?;
+        }
+
+        Ok(())
+    }
+}
+
+// NaN never counts as close.
+fn is_close(analytic: f64, numeric: f64, config: &GradcheckConfig) -> bool {
+    (analytic - numeric).abs() <= config.atol + config.rtol * numeric.abs()
+}
+
+/// Compares the gradients computed by `backward` against central finite
+/// differences using the default config.
+pub fn gradcheck<F>(inputs: &[f64], f: F) -> GradcheckReport
+where
+    F: Fn(&[Value]) -> Value,
+{
+    gradcheck_with(inputs, &GradcheckConfig::default(), f)
+}
+
+pub fn gradcheck_with<F>(inputs: &[f64], config: &GradcheckConfig, f: F) -> GradcheckReport
+where
+    F: Fn(&[Value]) -> Value,
+{
+    let eval = |inputs: &[f64]| {
+        let values = inputs.iter().map(|&x| Value::new(x)).collect::<Vec<_>>();
+        f(&values).data()
+    };
+
+    let values = inputs.iter().map(|&x| Value::new(x)).collect::<Vec<_>>();
+    let out = f(&values);
+    out.backward();
+    let analytic = values.iter().map(|v| v.grad()).collect::<Vec<_>>();
+
+    let mut numeric = Vec::with_capacity(inputs.len());
+    let mut shifted = inputs.to_vec();
+    for i in 0..inputs.len() {
+        shifted[i] = inputs[i] + config.epsilon;
+        let plus = eval(&shifted);
+        shifted[i] = inputs[i] - config.epsilon;
+        let minus = eval(&shifted);
+        shifted[i] = inputs[i];
+
+        numeric.push((plus - minus) / (2.0 * config.epsilon));
+    }
+
+    let mismatches = analytic.iter()
+        .zip(numeric.iter())
+        .enumerate()
+        .filter(|(_, (&a, &n))| !is_close(a, n, config))
+        .map(|(input, (&analytic, &numeric))| GradMismatch { input, analytic, numeric })
+        .collect();
+
+    GradcheckReport {
+        output: out.data(),
+        analytic,
+        numeric,
+        mismatches,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passes_for_correct_grads() {
+        let report = gradcheck(&[1.5, -0.5], |x| &x[0] * &x[1] + x[0].exp());
+        report.assert_ok();
+        assert_eq!(report.analytic.len(), 2);
+    }
+
+    #[test]
+    fn test_reports_mismatch() {
+        // clamp has a zero gradient outside of its range, but a finite
+        // difference across the boundary sees a slope.
+        let report = gradcheck(&[1.0, 0.5], |x| &x[0].clamp(-1.0, 1.0) + &x[1]);
+        assert!(!report.is_ok());
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].input, 0);
+    }
+}
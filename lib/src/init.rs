@@ -0,0 +1,176 @@
+use rand::Rng;
+
+use crate::Activation;
+
+/// Weight initialization strategy for a layer with `fan_in` inputs and
+/// `fan_out` outputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    /// Glorot uniform, `U(-a, a)` with `a = sqrt(6 / (fan_in + fan_out))`.
+    XavierUniform,
+    /// Glorot normal, `N(0, 2 / (fan_in + fan_out))`.
+    XavierNormal,
+    /// Kaiming uniform, `U(-a, a)` with `a = sqrt(6 / fan_in)`.
+    HeUniform,
+    /// Kaiming normal, `N(0, 2 / fan_in)`.
+    HeNormal,
+    /// `U(-a, a)` with `a = sqrt(3 / fan_in)`.
+    LecunUniform,
+    /// `N(0, 1 / fan_in)`.
+    LecunNormal,
+    /// Rows (or columns, whichever are fewer) form an orthonormal set,
+    /// scaled by the gain.
+    Orthogonal(f64),
+    Zeros,
+    Constant(f64),
+}
+
+pub(crate) fn sample_normal<R: Rng + ?Sized>(rng: &mut R, std_dev: f64) -> f64 {
+    // Box-Muller, 1 - u keeps the logarithm away from zero.
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    std_dev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+fn sample_uniform<R: Rng + ?Sized>(rng: &mut R, limit: f64) -> f64 {
+    rng.gen_range(-limit..=limit)
+}
+
+fn orthogonal<R: Rng + ?Sized>(fan_in: usize, fan_out: usize, gain: f64, rng: &mut R) -> Vec<Vec<f64>> {
+    // Orthonormalize the shorter side with Gram-Schmidt.
+    let (count, len) = if fan_out <= fan_in { (fan_out, fan_in) } else { (fan_in, fan_out) };
+    let mut vectors: Vec<Vec<f64>> = Vec::with_capacity(count);
+    while vectors.len() < count {
+        let mut v = (0..len).map(|_| sample_normal(rng, 1.0)).collect::<Vec<_>>();
+        for u in &vectors {
+            let dot = v.iter().zip(u).map(|(a, b)| a * b).sum::<f64>();
+            v.iter_mut().zip(u).for_each(|(a, b)| *a -= dot * b);
+        }
+        let norm = v.iter().map(|a| a * a).sum::<f64>().sqrt();
+        if norm > 1e-10 {
+            vectors.push(v.into_iter().map(|a| a / norm).collect());
+        }
+    }
+
+    if fan_out <= fan_in {
+        vectors.into_iter()
+            .map(|row| row.into_iter().map(|x| x * gain).collect())
+            .collect()
+    } else {
+        (0..fan_out)
+            .map(|o| (0..fan_in).map(|i| vectors[i][o] * gain).collect())
+            .collect()
+    }
+}
+
+impl Init {
+    /// Sensible default for a layer followed by `activation`: He for the ReLU
+    /// family, Xavier for everything else including the output layer.
+    pub fn default_for(activation: Activation) -> Init {
+        match activation {
+            Activation::Relu
+            | Activation::LeakyRelu(_)
+            | Activation::Elu(_)
+            | Activation::Gelu
+            | Activation::Silu => Init::HeNormal,
+            Activation::Identity
+            | Activation::Tanh
+            | Activation::Sigmoid
+            | Activation::Softplus => Init::XavierUniform,
+        }
+    }
+
+    /// Weights in `[fan_out, fan_in]` layout, one row per neuron.
+    pub fn weights<R: Rng + ?Sized>(&self, fan_in: usize, fan_out: usize, rng: &mut R) -> Vec<Vec<f64>> {
+        if let Init::Orthogonal(gain) = self {
+            return orthogonal(fan_in, fan_out, *gain, rng);
+        }
+
+        let fan_sum = (fan_in + fan_out).max(1) as f64;
+        let fan_in_f = fan_in.max(1) as f64;
+
+        let sample = |rng: &mut R| match self {
+            Init::XavierUniform => sample_uniform(rng, (6.0 / fan_sum).sqrt()),
+            Init::XavierNormal => sample_normal(rng, (2.0 / fan_sum).sqrt()),
+            Init::HeUniform => sample_uniform(rng, (6.0 / fan_in_f).sqrt()),
+            Init::HeNormal => sample_normal(rng, (2.0 / fan_in_f).sqrt()),
+            Init::LecunUniform => sample_uniform(rng, (3.0 / fan_in_f).sqrt()),
+            Init::LecunNormal => sample_normal(rng, (1.0 / fan_in_f).sqrt()),
+            Init::Zeros => 0.0,
+            Init::Constant(c) => *c,
+            Init::Orthogonal(_) => unreachable!("handled above"),
+        };
+
+        (0..fan_out)
+            .map(|_| (0..fan_in).map(|_| sample(rng)).collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    fn stats(weights: &[Vec<f64>]) -> (f64, f64) {
+        let all = weights.concat();
+        let mean = all.iter().sum::<f64>() / all.len() as f64;
+        let var = all.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / all.len() as f64;
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn test_distributions() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let cases = [
+            (Init::XavierUniform, (2.0f64 / 300.0).sqrt()),
+            (Init::XavierNormal, (2.0f64 / 300.0).sqrt()),
+            (Init::HeUniform, (2.0f64 / 200.0).sqrt()),
+            (Init::HeNormal, (2.0f64 / 200.0).sqrt()),
+            (Init::LecunUniform, (1.0f64 / 200.0).sqrt()),
+            (Init::LecunNormal, (1.0f64 / 200.0).sqrt()),
+        ];
+
+        for (init, expected_std) in cases {
+            let weights = init.weights(200, 100, &mut rng);
+            assert_eq!(weights.len(), 100);
+            assert_eq!(weights[0].len(), 200);
+
+            let (mean, std) = stats(&weights);
+            assert!(mean.abs() < 0.01, "{:?} mean {}", init, mean);
+            assert!((std / expected_std - 1.0).abs() < 0.05, "{:?} std {} expected {}", init, std, expected_std);
+        }
+    }
+
+    #[test]
+    fn test_orthogonal() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for (fan_in, fan_out) in [(6, 4), (4, 6), (5, 5)] {
+            let w = Init::Orthogonal(1.0).weights(fan_in, fan_out, &mut rng);
+            assert_eq!((w.len(), w[0].len()), (fan_out, fan_in));
+
+            // W W^T = I when fan_out <= fan_in, W^T W = I otherwise.
+            let n = fan_in.min(fan_out);
+            for a in 0..n {
+                for b in 0..n {
+                    let dot = if fan_out <= fan_in {
+                        (0..fan_in).map(|k| w[a][k] * w[b][k]).sum::<f64>()
+                    } else {
+                        (0..fan_out).map(|k| w[k][a] * w[k][b]).sum::<f64>()
+                    };
+                    let expected = if a == b { 1.0 } else { 0.0 };
+                    assert!((dot - expected).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_constant() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(Init::Zeros.weights(2, 1, &mut rng), vec![vec![0.0, 0.0]]);
+        assert_eq!(Init::Constant(0.5).weights(1, 2, &mut rng), vec![vec![0.5], vec![0.5]]);
+    }
+}
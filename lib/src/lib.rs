@@ -2,30 +2,69 @@
 mod nn;
 mod loss;
 mod value;
+mod gradcheck;
+mod tensor;
+mod tape;
+mod optim;
+mod scheduler;
+mod activation;
+mod init;
+mod rng;
+mod serialize;
+mod safetensors;
+mod onnx;
+mod dot;
+mod stats;
+mod metrics;
 
 use std::iter::zip;
 
 pub use nn::*;
 pub use loss::*;
 use rand::Rng;
-use rand::distributions::Uniform;
 pub use value::*;
+pub use gradcheck::*;
+pub use tensor::*;
+pub use tape::*;
+pub use optim::*;
+pub use scheduler::*;
+pub use activation::*;
+pub use init::*;
+pub use rng::*;
+pub use serialize::*;
+pub use safetensors::*;
+pub use onnx::*;
+pub use dot::*;
+pub use stats::*;
+pub use metrics::*;
 
 pub fn create_random_floats(n: usize) -> Vec<f64> {
-    let mut rng = rand::thread_rng();
-    let mut floats = Vec::with_capacity(n);
+    with_rng(|rng| {
+        let mut floats = Vec::with_capacity(n);
 
-    for _ in 0..n {
-        let random_float: f64 = rng.gen();
-        floats.push(random_float);
-    }
+        for _ in 0..n {
+            let random_float: f64 = rng.gen();
+            floats.push(random_float);
+        }
 
-    floats
+        floats
+    })
 }
 
+/// Shifted by the largest value before exponentiating so no logit can
+/// overflow. The shift is a plain constant: it cancels out of the result and
+/// its gradient, so there is no need to route it through the graph.
 pub fn softmax(values: &[Value]) -> Vec<Value> {
-    let max = values.iter().fold(Value::new(0.0), |a, b| a.max(b));
-    let exps = values.iter().map(|v| v.sub(&max).exp()).collect::<Vec<_>>();
+    softmax_with_temperature(values, 1.0)
+}
+
+/// Softmax of `values / temperature`. Temperatures above 1 flatten the
+/// distribution, below 1 sharpen it.
+pub fn softmax_with_temperature(values: &[Value], temperature: f64) -> Vec<Value> {
+    assert!(temperature > 0.0, "softmax temperature must be positive, got {}", temperature);
+
+    let max = values.iter().map(|v| v.data()).fold(f64::NEG_INFINITY, f64::max);
+    let exps = values.iter().map(|v| ((v - max) / temperature).exp()).collect::<Vec<_>>();
     let sum = exps.iter().sum::<Value>();
     exps.iter().map(|v| v.div(&sum)).collect::<Vec<Value>>()
 }
@@ -62,10 +101,61 @@ pub fn calculate_accuracy(y: &[u32], y_hat: &[u32]) -> f64 {
     correct as f64 / y.len() as f64
 }
 
+/// Samples `size` weights from `N(0, 2 / fan_in)`.
 pub fn he_initialization(size: usize, fan_in: usize) -> Vec<f64> {
     let std_dev = (2.0 / fan_in as f64).sqrt();
-    let normal = Uniform::new(-std_dev, std_dev);
-    let mut rng = rand::thread_rng();
 
-    (0..size).map(|_| rng.sample(&normal)).collect()
-}
\ No newline at end of file
+    with_rng(|rng| (0..size).map(|_| init::sample_normal(rng, std_dev)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(data: &[f64]) -> Vec<Value> {
+        data.iter().map(|&x| Value::new(x)).collect()
+    }
+
+    #[test]
+    fn test_softmax_large_logits() {
+        for logits in [[1000.0, 999.0, 998.0], [-1000.0, -1001.0, -1002.0]] {
+            let p = softmax(&values(&logits));
+            let sum = 1.0 + (-1.0f64).exp() + (-2.0f64).exp();
+            let expected = [1.0 / sum, (-1.0f64).exp() / sum, (-2.0f64).exp() / sum];
+            for (p, e) in p.iter().zip(expected) {
+                assert!((p.data() - e).abs() < 1e-12, "{:?}: {} != {}", logits, p.data(), e);
+            }
+        }
+
+        // All negative logits used to be shifted by 0 and underflow to 0 / 0.
+        let x = values(&[-800.0, -801.0]);
+        let p = softmax(&x);
+        assert!(p.iter().all(|p| p.data().is_finite()));
+        p[0].backward();
+        assert!(x.iter().all(|x| x.grad().is_finite() && x.grad() != 0.0));
+    }
+
+    #[test]
+    fn test_softmax_shift_is_constant() {
+        let p = softmax(&values(&[0.5, -1.0, 2.0]));
+        // The shift by the max is a constant, not a Max node in the graph.
+        assert_eq!(p[0].graph_stats().op_count("Max"), 0);
+    }
+
+    #[test]
+    fn test_softmax_temperature() {
+        let x = values(&[1.0, 2.0, 3.0]);
+        let hot = softmax_with_temperature(&x, 0.5);
+        let scaled = softmax(&values(&[2.0, 4.0, 6.0]));
+        for (a, b) in hot.iter().zip(scaled.iter()) {
+            assert!((a.data() - b.data()).abs() < 1e-12);
+        }
+
+        let flat = softmax_with_temperature(&x, 100.0);
+        assert!(flat.iter().all(|p| (p.data() - 1.0 / 3.0).abs() < 0.01));
+
+        gradcheck(&[0.3, -1.2, 2.0], |x| {
+            softmax_with_temperature(x, 2.5).iter().zip([1.0, -2.0, 0.5]).map(|(p, w)| p * w).sum::<Value>()
+        }).assert_ok();
+    }
+}
@@ -1,27 +1,279 @@
 use std::iter::zip;
 
+use crate::Tensor;
 use crate::Value;
+use crate::value::FusedOPType;
+
+/// How the per-element losses are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reduction {
+    /// Keep one loss per element, a `[n]` tensor.
+    None,
+    /// Scalar sum of the losses.
+    Sum,
+    /// Scalar mean of the losses.
+    #[default]
+    Mean,
+}
+
+impl Reduction {
+    pub fn reduce(&self, losses: Vec<Value>) -> Tensor {
+        match self {
+            Reduction::None => {
+                let n = losses.len();
+                Tensor::new(losses, &[n])
+            },
+            Reduction::Sum => Tensor::scalar(losses.iter().sum()),
+            Reduction::Mean => {
+                let n = losses.len().max(1) as f64;
+                Tensor::scalar(losses.iter().sum::<Value>() / n)
+            },
+        }
+    }
+}
+
+const EPS: f64 = 1e-12;
 
 pub fn cross_entropy_loss(y: &[Value], y_hat: &[Value]) -> Value {
-    zip(y.iter(), y_hat.iter())
+    -zip(y.iter(), y_hat.iter())
         .map(|(y, y_hat)| {
             let clipped_y_hat = y_hat.max(&Value::new(1e-15)).min(&Value::new(1.0 - 1e-15));
-            y.mul(&clipped_y_hat.log())
+            y * clipped_y_hat.log()
+        })
+        .sum::<Value>()
+}
+
+// Softmax probabilities and log-sum-exp of the logits, shifted by the max so
+// the exponentials can not overflow.
+fn softmax_lse(logits: &[Value]) -> (Vec<f64>, f64) {
+    let max = logits.iter().map(|v| v.data()).fold(f64::NEG_INFINITY, f64::max);
+    let exps = logits.iter().map(|v| (v.data() - max).exp()).collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f64>();
+    (exps.iter().map(|e| e / sum).collect(), max + sum.ln())
+}
+
+/// `z_i - log(sum_j exp(z_j))` for each logit, stable for logits of any
+/// size. Every output is a single node with its own fused backward.
+pub fn log_softmax(logits: &[Value]) -> Vec<Value> {
+    let (probs, lse) = softmax_lse(logits);
+    logits.iter()
+        .enumerate()
+        .map(|(i, z)| {
+            let local_grads = probs.iter()
+                .enumerate()
+                .map(|(j, p)| if i == j { 1.0 - p } else { -p })
+                .collect();
+            Value::fused(logits, FusedOPType::LogSoftmax, z.data() - lse, local_grads)
         })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CrossEntropyConfig {
+    /// Moves this much of the target probability evenly onto all classes.
+    pub label_smoothing: f64,
+    /// Scales the loss of each class, e.g. to counter class imbalance.
+    pub class_weights: Option<Vec<f64>>,
+}
+
+/// Cross entropy of `softmax(logits)` against the class `target`, computed
+/// from the logits with log-sum-exp. Unlike `softmax` followed by
+/// `cross_entropy_loss` nothing is clipped, so confidently wrong
+/// predictions keep their full gradient `softmax(logits) - one_hot(target)`.
+pub fn cross_entropy_with_logits(logits: &[Value], target: usize) -> Value {
+    cross_entropy_with_logits_with(logits, target, &CrossEntropyConfig::default())
+}
+
+/// `cross_entropy_with_logits` with label smoothing and class weights:
+/// `-sum_j w_j q_j log p_j` with `q` the smoothed one-hot target.
+pub fn cross_entropy_with_logits_with(logits: &[Value], target: usize, config: &CrossEntropyConfig) -> Value {
+    let n = logits.len();
+    assert!(target < n, "cross_entropy_with_logits: target {} out of {} classes", target, n);
+    if let Some(weights) = &config.class_weights {
+        assert_eq!(weights.len(), n, "cross_entropy_with_logits: one weight per class");
+    }
+
+    let (probs, lse) = softmax_lse(logits);
+    let smoothing = config.label_smoothing;
+    let q = (0..n)
+        .map(|j| {
+            let weight = config.class_weights.as_ref().map_or(1.0, |w| w[j]);
+            let target_prob = if j == target { 1.0 - smoothing } else { 0.0 };
+            weight * (target_prob + smoothing / n as f64)
+        })
+        .collect::<Vec<_>>();
+
+    let loss = zip(&q, logits).map(|(q, z)| q * (lse - z.data())).sum::<f64>();
+    let total = q.iter().sum::<f64>();
+    let local_grads = zip(&probs, &q).map(|(p, q)| p * total - q).collect();
+
+    Value::fused(logits, FusedOPType::CrossEntropy, loss, local_grads)
+}
+
+/// Mean of `cross_entropy_with_logits` over a minibatch, so one `backward`
+/// gives the averaged gradients.
+pub fn batch_cross_entropy_with_logits(logits: &[Vec<Value>], targets: &[usize]) -> Value {
+    batch_cross_entropy_with_logits_with(logits, targets, &CrossEntropyConfig::default())
+}
+
+/// With class weights the sum is divided by the total weight of the targets
+/// rather than the batch size, like PyTorch's weighted mean.
+pub fn batch_cross_entropy_with_logits_with(
+    logits: &[Vec<Value>],
+    targets: &[usize],
+    config: &CrossEntropyConfig,
+) -> Value {
+    assert_eq!(logits.len(), targets.len(), "batch_cross_entropy_with_logits: one target per sample");
+    assert!(!targets.is_empty(), "batch_cross_entropy_with_logits: empty batch");
+
+    let total_weight = match &config.class_weights {
+        Some(weights) => targets.iter().map(|&t| weights[t]).sum::<f64>(),
+        None => targets.len() as f64,
+    };
+
+    zip(logits, targets)
+        .map(|(logits, &target)| cross_entropy_with_logits_with(logits, target, config))
         .sum::<Value>()
-        .mul(&Value::new(-1.0))
+        / total_weight
+}
+
+/// Mean squared error over every element of a minibatch.
+pub fn batch_mse_loss(y: &[Vec<Value>], y_hat: &[Vec<Value>]) -> Value {
+    assert_eq!(y.len(), y_hat.len(), "batch_mse_loss: target and prediction batch sizes differ");
+    for (i, (y, y_hat)) in zip(y, y_hat).enumerate() {
+        assert_eq!(y.len(), y_hat.len(), "batch_mse_loss: target and prediction sizes differ for sample {}", i);
+    }
+    let y = y.concat();
+    mse_loss(&y, &y_hat.concat(), Reduction::Mean).item()
+}
+
+/// Squared error `(y_hat - y)^2`.
+pub fn mse_loss(y: &[Value], y_hat: &[Value], reduction: Reduction) -> Tensor {
+    assert_eq!(y.len(), y_hat.len(), "mse_loss: target and prediction lengths differ");
+    reduction.reduce(zip(y, y_hat).map(|(y, y_hat)| (y_hat - y).square()).collect())
+}
+
+/// Absolute error `|y_hat - y|`.
+pub fn mae_loss(y: &[Value], y_hat: &[Value], reduction: Reduction) -> Tensor {
+    assert_eq!(y.len(), y_hat.len(), "mae_loss: target and prediction lengths differ");
+    reduction.reduce(zip(y, y_hat).map(|(y, y_hat)| (y_hat - y).abs()).collect())
+}
+
+/// Squared error for errors up to `delta` and linear beyond, so outliers
+/// do not dominate the gradient.
+pub fn huber_loss(y: &[Value], y_hat: &[Value], delta: f64, reduction: Reduction) -> Tensor {
+    assert_eq!(y.len(), y_hat.len(), "huber_loss: target and prediction lengths differ");
+    let delta_value = Value::new(delta);
+    reduction.reduce(zip(y, y_hat)
+        .map(|(y, y_hat)| {
+            let error = (y_hat - y).abs();
+            let quadratic = error.min(&delta_value);
+            quadratic.square() * 0.5 + (error - &quadratic) * delta
+        })
+        .collect())
+}
+
+/// Binary cross entropy of probabilities `p` against targets in `[0, 1]`.
+/// `p` is clamped away from 0 and 1, prefer
+/// `binary_cross_entropy_with_logits` when the logits are available.
+pub fn binary_cross_entropy(y: &[Value], p: &[Value], reduction: Reduction) -> Tensor {
+    assert_eq!(y.len(), p.len(), "binary_cross_entropy: target and prediction lengths differ");
+    reduction.reduce(zip(y, p)
+        .map(|(y, p)| {
+            let p = p.clamp(EPS, 1.0 - EPS);
+            -(y * p.log() + (1.0 - y) * (1.0 - &p).log())
+        })
+        .collect())
+}
+
+/// Binary cross entropy of `sigmoid(z)`, computed as `softplus(z) - z * y`
+/// which stays finite and keeps its gradient for any logit.
+pub fn binary_cross_entropy_with_logits(y: &[Value], z: &[Value], reduction: Reduction) -> Tensor {
+    assert_eq!(y.len(), z.len(), "binary_cross_entropy_with_logits: target and logit lengths differ");
+    reduction.reduce(zip(y, z).map(|(y, z)| z.softplus() - z * y).collect())
+}
+
+/// Binary hinge loss `max(0, 1 - y * score)` for labels `-1` and `1`, as in
+/// the micrograd moons demo.
+pub fn hinge_loss(y: &[Value], scores: &[Value], reduction: Reduction) -> Tensor {
+    assert_eq!(y.len(), scores.len(), "hinge_loss: target and score lengths differ");
+    reduction.reduce(zip(y, scores).map(|(y, s)| (1.0 - y * s).relu()).collect())
+}
+
+/// Multi-class max-margin loss of one sample, `max(0, margin - s[target] +
+/// s[j])` for every class `j`, zero for the target itself. `Mean` divides by
+/// the number of classes like PyTorch's `multi_margin_loss`.
+pub fn multi_margin_loss(scores: &[Value], target: usize, margin: f64, reduction: Reduction) -> Tensor {
+    assert!(target < scores.len(), "multi_margin_loss: target {} out of {} classes", target, scores.len());
+    let target_score = &scores[target];
+    reduction.reduce(scores.iter()
+        .enumerate()
+        .map(|(j, s)| {
+            if j == target {
+                Value::new(0.0)
+            } else {
+                (s - target_score + margin).relu()
+            }
+        })
+        .collect())
+}
+
+/// `p * (ln p - ln q)` of a target distribution `p` and a predicted
+/// distribution `q`. Terms with `p = 0` contribute nothing.
+pub fn kl_divergence(p: &[Value], q: &[Value], reduction: Reduction) -> Tensor {
+    assert_eq!(p.len(), q.len(), "kl_divergence: distribution lengths differ");
+    reduction.reduce(zip(p, q)
+        .map(|(p, q)| {
+            if p.data() == 0.0 {
+                Value::new(0.0)
+            } else {
+                p * (p.log() - q.max(&Value::new(EPS)).log())
+            }
+        })
+        .collect())
+}
+
+fn cosine_similarity(a: &[Value], b: &[Value]) -> Value {
+    let dot = zip(a, b).map(|(a, b)| a * b).sum::<Value>();
+    let norm_a = a.iter().map(|a| a.square()).sum::<Value>().sqrt();
+    let norm_b = b.iter().map(|b| b.square()).sum::<Value>().sqrt();
+    dot / (norm_a * norm_b).max(&Value::new(EPS))
+}
+
+/// For each pair, `1 - cos(x1, x2)` when `y` is 1 (similar) and
+/// `max(0, cos(x1, x2) - margin)` when `y` is -1 (dissimilar).
+pub fn cosine_embedding_loss(
+    x1: &[Vec<Value>],
+    x2: &[Vec<Value>],
+    y: &[f64],
+    margin: f64,
+    reduction: Reduction,
+) -> Tensor {
+    assert!(x1.len() == x2.len() && x1.len() == y.len(), "cosine_embedding_loss: batch sizes differ");
+    reduction.reduce((0..y.len())
+        .map(|i| {
+            let cos = cosine_similarity(&x1[i], &x2[i]);
+            if y[i] > 0.0 {
+                1.0 - cos
+            } else {
+                (cos - margin).relu()
+            }
+        })
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gradcheck;
 
     #[test]
     fn test() {
         let y = [Value::new(0.5), Value::new(0.1)];
         let y_hat = [Value::new(0.4), Value::new(0.2)];
         let loss = super::cross_entropy_loss(&y, &y_hat);
-        println!("{:#?}", loss.data())
+        let expected = -(0.5 * 0.4f64.ln() + 0.1 * 0.2f64.ln());
+        assert!((loss.data() - expected).abs() < 1e-12);
     }
 
     #[test]
@@ -29,6 +281,184 @@ mod tests {
         let y = [Value::new(0.5), Value::new(0.5)];
         let y_hat = [Value::new(0.0), Value::new(1.0)];
         let loss = super::cross_entropy_loss(&y, &y_hat);
-        println!("{:#?}", loss.data())
+        assert!(loss.data().is_finite());
+    }
+
+    #[test]
+    fn test_cross_entropy_grad() {
+        gradcheck(&[0.5, 0.1, 0.4, 0.4, 0.2, 0.4], |x| {
+            cross_entropy_loss(&x[..3], &x[3..])
+        }).assert_ok();
+    }
+
+    fn values(data: &[f64]) -> Vec<Value> {
+        data.iter().map(|&x| Value::new(x)).collect()
+    }
+
+    #[test]
+    fn test_reduction() {
+        let y = values(&[1.0, 2.0, 3.0]);
+        let y_hat = values(&[1.5, 2.0, 1.0]);
+
+        let none = mse_loss(&y, &y_hat, Reduction::None);
+        assert_eq!(none.shape(), &[3]);
+        assert_eq!(none.data(), vec![0.25, 0.0, 4.0]);
+        assert_eq!(mse_loss(&y, &y_hat, Reduction::Sum).item().data(), 4.25);
+        assert_eq!(mse_loss(&y, &y_hat, Reduction::Mean).item().data(), 4.25 / 3.0);
+        assert_eq!(mae_loss(&y, &y_hat, Reduction::default()).item().data(), 2.5 / 3.0);
+    }
+
+    #[test]
+    fn test_known_values() {
+        let huber = huber_loss(&values(&[0.0, 0.0]), &values(&[0.5, -3.0]), 1.0, Reduction::None).data();
+        assert_eq!(huber, vec![0.125, 2.5]);
+
+        let hinge = hinge_loss(&values(&[1.0, -1.0, 1.0]), &values(&[2.0, 0.5, 0.3]), Reduction::None).data();
+        assert_eq!(hinge, vec![0.0, 1.5, 0.7]);
+
+        let margin = multi_margin_loss(&values(&[0.1, 0.8, 0.5]), 1, 1.0, Reduction::None).data();
+        assert!((margin[0] - 0.3).abs() < 1e-12 && margin[1] == 0.0 && (margin[2] - 0.7).abs() < 1e-12);
+
+        let kl = kl_divergence(&values(&[0.5, 0.5, 0.0]), &values(&[0.25, 0.75, 0.0]), Reduction::Sum).item().data();
+        assert!((kl - (0.5 * 2.0f64.ln() + 0.5 * (2.0f64 / 3.0).ln())).abs() < 1e-12);
+
+        let a = vec![values(&[1.0, 0.0]), values(&[1.0, 0.0])];
+        let b = vec![values(&[0.0, 2.0]), values(&[3.0, 0.0])];
+        let cos = cosine_embedding_loss(&a, &b, &[1.0, -1.0], 0.5, Reduction::None).data();
+        assert_eq!(cos, vec![1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_bce_with_logits_is_stable() {
+        let y = values(&[1.0, 0.0, 1.0, 0.0]);
+        let z = values(&[0.3, -1.2, -800.0, 800.0]);
+        let p = z.iter().map(|z| z.sigmoid()).collect::<Vec<_>>();
+
+        let from_logits = binary_cross_entropy_with_logits(&y, &z, Reduction::None);
+        let from_probs = binary_cross_entropy(&y, &p, Reduction::None);
+        for (a, b) in from_logits.data().iter().zip(from_probs.data()).take(2) {
+            assert!((a - b).abs() < 1e-12);
+        }
+
+        // Confidently wrong predictions keep a finite loss and a gradient.
+        assert_eq!(from_logits.data()[2], 800.0);
+        assert_eq!(from_logits.data()[3], 800.0);
+        from_logits.sum_all().backward();
+        assert_eq!(z[2].grad(), -1.0);
+        assert_eq!(z[3].grad(), 1.0);
+    }
+
+    #[test]
+    fn test_grads() {
+        let mean = Reduction::Mean;
+        gradcheck(&[0.2, -1.0, 3.0, 0.5, 0.1, 2.0], |x| mse_loss(&x[..3], &x[3..], mean).item()).assert_ok();
+        gradcheck(&[0.2, -1.0, 3.0, 0.5, 0.1, 2.0], |x| mae_loss(&x[..3], &x[3..], mean).item()).assert_ok();
+        gradcheck(&[0.2, -1.0, 3.0, 0.5, 0.1, 2.0], |x| huber_loss(&x[..3], &x[3..], 1.0, mean).item()).assert_ok();
+        gradcheck(&[1.0, 0.0, 0.3, 0.7, 0.4, 0.9], |x| binary_cross_entropy(&x[..3], &x[3..], mean).item()).assert_ok();
+        gradcheck(&[1.0, 0.0, 0.3, 2.5, -0.4, 0.9], |x| binary_cross_entropy_with_logits(&x[..3], &x[3..], mean).item()).assert_ok();
+        gradcheck(&[1.0, -1.0, 1.0, 0.5, 0.1, -2.0], |x| hinge_loss(&x[..3], &x[3..], mean).item()).assert_ok();
+        gradcheck(&[0.1, 0.8, 0.5, -0.6], |x| multi_margin_loss(x, 2, 1.0, mean).item()).assert_ok();
+        gradcheck(&[0.2, 0.3, 0.5, 0.4, 0.4, 0.2], |x| kl_divergence(&x[..3], &x[3..], Reduction::Sum).item()).assert_ok();
+        gradcheck(&[0.3, -1.0, 2.0, 0.5, 1.5, -0.2], |x| {
+            cosine_embedding_loss(&[x[..3].to_vec()], &[x[3..].to_vec()], &[1.0], 0.0, mean).item()
+        }).assert_ok();
+        gradcheck(&[0.3, 1.0, 2.0, 0.5, 1.5, 0.2], |x| {
+            cosine_embedding_loss(&[x[..3].to_vec()], &[x[3..].to_vec()], &[-1.0], 0.1, mean).item()
+        }).assert_ok();
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let logits = values(&[1.0, 2.0, 0.5]);
+        let sum = logits.iter().map(|z| z.data().exp()).sum::<f64>();
+        for (out, z) in log_softmax(&logits).iter().zip(&logits) {
+            assert!((out.data() - (z.data() - sum.ln())).abs() < 1e-12);
+        }
+
+        let out = log_softmax(&values(&[1000.0, 0.0, -1000.0]));
+        assert_eq!(out[0].data(), 0.0);
+        assert_eq!(out[1].data(), -1000.0);
+        assert_eq!(out[2].data(), -2000.0);
+
+        gradcheck(&[0.3, -1.2, 2.0, 0.1], |x| {
+            log_softmax(x).iter().enumerate().map(|(i, v)| v * (i as f64 + 1.0)).sum::<Value>()
+        }).assert_ok();
+    }
+
+    #[test]
+    fn test_cross_entropy_with_logits() {
+        let logits = values(&[0.3, -1.2, 2.0]);
+        let loss = cross_entropy_with_logits(&logits, 1);
+        assert!((loss.data() + log_softmax(&logits)[1].data()).abs() < 1e-12);
+        assert_eq!(loss.graph_stats().node_count, 4);
+
+        gradcheck(&[0.3, -1.2, 2.0], |x| cross_entropy_with_logits(x, 0)).assert_ok();
+
+        let config = CrossEntropyConfig {
+            label_smoothing: 0.1,
+            class_weights: Some(vec![0.5, 2.0, 1.0]),
+        };
+        gradcheck(&[0.3, -1.2, 2.0], |x| cross_entropy_with_logits_with(x, 1, &config)).assert_ok();
+
+        let log_p = log_softmax(&logits).iter().map(|v| v.data()).collect::<Vec<_>>();
+        let q = [0.5 * 0.1 / 3.0, 2.0 * (0.9 + 0.1 / 3.0), 0.1 / 3.0];
+        let expected = -(0..3).map(|j| q[j] * log_p[j]).sum::<f64>();
+        assert!((cross_entropy_with_logits_with(&logits, 1, &config).data() - expected).abs() < 1e-12);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_confident_wrong_prediction() {
+        let logits = values(&[50.0, -50.0]);
+        let loss = cross_entropy_with_logits(&logits, 1);
+        loss.backward();
+        assert_eq!(loss.data(), 100.0);
+        assert_eq!(logits[0].grad(), 1.0);
+        assert_eq!(logits[1].grad(), -1.0);
+
+        // Clipping the probability first loses the gradient entirely.
+        let logits = values(&[50.0, -50.0]);
+        let probs = crate::softmax(&logits);
+        let clipped = cross_entropy_loss(&[Value::new(0.0), Value::new(1.0)], &probs);
+        clipped.backward();
+        assert!(clipped.data() < 35.0);
+        assert_eq!(logits[1].grad(), 0.0);
+    }
+
+    #[test]
+    fn test_batch_losses() {
+        let logits = vec![values(&[0.3, -1.2, 2.0]), values(&[1.0, 0.5, -0.5])];
+        let targets = [2, 0];
+
+        let loss = batch_cross_entropy_with_logits(&logits, &targets);
+        let expected = (cross_entropy_with_logits(&logits[0], 2).data()
+            + cross_entropy_with_logits(&logits[1], 0).data()) / 2.0;
+        assert!((loss.data() - expected).abs() < 1e-12);
+
+        // The gradient of the mean is the average of the per-sample gradients.
+        loss.backward();
+        let probs = crate::softmax(&logits[1]);
+        assert!((logits[1][0].grad() - (probs[0].data() - 1.0) / 2.0).abs() < 1e-12);
+
+        let config = CrossEntropyConfig {
+            class_weights: Some(vec![2.0, 1.0, 0.5]),
+            ..Default::default()
+        };
+        let weighted = batch_cross_entropy_with_logits_with(&logits, &targets, &config);
+        let expected = (0.5 * cross_entropy_with_logits(&logits[0], 2).data()
+            + 2.0 * cross_entropy_with_logits(&logits[1], 0).data()) / 2.5;
+        assert!((weighted.data() - expected).abs() < 1e-12);
+
+        let y = vec![values(&[1.0, 2.0]), values(&[0.0, 0.0])];
+        let y_hat = vec![values(&[1.0, 3.0]), values(&[2.0, 0.0])];
+        assert_eq!(batch_mse_loss(&y, &y_hat).data(), 5.0 / 4.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "sizes differ for sample 0")]
+    fn test_batch_mse_loss_ragged() {
+        // Same total length, but the rows do not line up.
+        let y = vec![values(&[1.0]), values(&[2.0, 3.0])];
+        let y_hat = vec![values(&[1.0, 2.0]), values(&[3.0])];
+        batch_mse_loss(&y, &y_hat);
+    }
+}
@@ -0,0 +1,354 @@
+use std::fmt;
+use std::iter::zip;
+
+/// How per-class scores are combined into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Average {
+    /// Unweighted mean over the classes.
+    #[default]
+    Macro,
+    /// Computed from the counts summed over all classes.
+    Micro,
+    /// Mean over the classes weighted by how often each one occurs.
+    Weighted,
+}
+
+/// Counts of actual (rows) against predicted (columns) classes. Built up one
+/// batch at a time with `update` and combined across workers with `merge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfusionMatrix {
+    num_classes: usize,
+    counts: Vec<u64>,
+}
+
+impl ConfusionMatrix {
+    pub fn new(num_classes: usize) -> ConfusionMatrix {
+        ConfusionMatrix {
+            num_classes,
+            counts: vec![0; num_classes * num_classes],
+        }
+    }
+
+    pub fn from_labels(num_classes: usize, actual: &[usize], predicted: &[usize]) -> ConfusionMatrix {
+        let mut matrix = ConfusionMatrix::new(num_classes);
+        matrix.update(actual, predicted);
+        matrix
+    }
+
+    fn index(&self, actual: usize, predicted: usize) -> usize {
+        assert!(
+            actual < self.num_classes && predicted < self.num_classes,
+            "class out of range: actual {} predicted {} with {} classes",
+            actual,
+            predicted,
+            self.num_classes
+        );
+        actual * self.num_classes + predicted
+    }
+
+    pub fn add(&mut self, actual: usize, predicted: usize) {
+        let index = self.index(actual, predicted);
+        self.counts[index] += 1;
+    }
+
+    pub fn update(&mut self, actual: &[usize], predicted: &[usize]) {
+        assert_eq!(actual.len(), predicted.len(), "update: actual and predicted lengths differ");
+        for (&a, &p) in zip(actual, predicted) {
+            self.add(a, p);
+        }
+    }
+
+    pub fn merge(&mut self, other: &ConfusionMatrix) {
+        assert_eq!(self.num_classes, other.num_classes, "merge: class counts differ");
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.fill(0);
+    }
+
+    pub fn num_classes(&self) -> usize {
+        self.num_classes
+    }
+
+    pub fn get(&self, actual: usize, predicted: usize) -> u64 {
+        self.counts[self.index(actual, predicted)]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn correct(&self) -> u64 {
+        (0..self.num_classes).map(|c| self.get(c, c)).sum()
+    }
+
+    /// Number of samples whose actual class is `class`.
+    pub fn support(&self, class: usize) -> u64 {
+        (0..self.num_classes).map(|p| self.get(class, p)).sum()
+    }
+
+    /// Number of samples predicted as `class`.
+    pub fn predicted(&self, class: usize) -> u64 {
+        (0..self.num_classes).map(|a| self.get(a, class)).sum()
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        ratio(self.correct(), self.total())
+    }
+
+    /// Scores of a class without predictions or samples are 0, like
+    /// scikit-learn's default `zero_division`.
+    pub fn precision(&self, class: usize) -> f64 {
+        ratio(self.get(class, class), self.predicted(class))
+    }
+
+    pub fn recall(&self, class: usize) -> f64 {
+        ratio(self.get(class, class), self.support(class))
+    }
+
+    pub fn f1(&self, class: usize) -> f64 {
+        f1(self.precision(class), self.recall(class))
+    }
+
+    pub fn precision_avg(&self, average: Average) -> f64 {
+        self.average(average, |c| self.precision(c))
+    }
+
+    pub fn recall_avg(&self, average: Average) -> f64 {
+        self.average(average, |c| self.recall(c))
+    }
+
+    pub fn f1_avg(&self, average: Average) -> f64 {
+        match average {
+            Average::Micro => f1(self.precision_avg(average), self.recall_avg(average)),
+            _ => self.average(average, |c| self.f1(c)),
+        }
+    }
+
+    fn average<F>(&self, average: Average, score: F) -> f64
+    where
+        F: Fn(usize) -> f64,
+    {
+        match average {
+            Average::Macro => (0..self.num_classes).map(score).sum::<f64>() / self.num_classes.max(1) as f64,
+            Average::Weighted => {
+                let weighted = (0..self.num_classes).map(|c| score(c) * self.support(c) as f64).sum::<f64>();
+                weighted / self.total().max(1) as f64
+            },
+            // Every false positive of one class is a false negative of
+            // another, so micro precision and recall are both the accuracy.
+            Average::Micro => self.accuracy(),
+        }
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 { 0.0 } else { a as f64 / b as f64 }
+}
+
+fn f1(precision: f64, recall: f64) -> f64 {
+    if precision + recall == 0.0 {
+        0.0
+    } else {
+        2.0 * precision * recall / (precision + recall)
+    }
+}
+
+impl fmt::Display for ConfusionMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.counts.iter()
+            .max()
+            .map_or(1, |m| m.to_string().len())
+            .max(self.num_classes.saturating_sub(1).to_string().len())
+            .max(4);
+
+        write!(f, "{:>w$} |", "a\\p", w = width)?;
+        for p in 0..self.num_classes {
+            write!(f, " {:>w$}", p, w = width)?;
+        }
+        writeln!(f, " | prec   rec    f1     support")?;
+
+        for a in 0..self.num_classes {
+            write!(f, "{:>w$} |", a, w = width)?;
+            for p in 0..self.num_classes {
+                write!(f, " {:>w$}", self.get(a, p), w = width)?;
+            }
+            writeln!(
+                f,
+                " | {:.4} {:.4} {:.4} {}",
+                self.precision(a),
+                self.recall(a),
+                self.f1(a),
+                self.support(a)
+            )?;
+        }
+
+        writeln!(
+            f,
+            "accuracy {:.4}, macro f1 {:.4}, weighted f1 {:.4}, {} samples",
+            self.accuracy(),
+            self.f1_avg(Average::Macro),
+            self.f1_avg(Average::Weighted),
+            self.total()
+        )
+    }
+}
+
+/// Fraction of samples whose target is among the `k` highest scores.
+pub fn top_k_accuracy(scores: &[Vec<f64>], targets: &[usize], k: usize) -> f64 {
+    assert_eq!(scores.len(), targets.len(), "top_k_accuracy: one target per sample");
+
+    let hits = zip(scores, targets)
+        .filter(|(scores, &target)| {
+            // Ties count against the target, so a constant output is not a hit,
+            // and neither is a NaN or infinite target score.
+            let higher = scores.iter().filter(|&&s| s >= scores[target]).count();
+            scores[target].is_finite() && higher <= k
+        })
+        .count();
+
+    ratio(hits as u64, targets.len() as u64)
+}
+
+/// Area under the ROC curve of a binary problem, the probability that a
+/// random positive scores above a random negative with ties counting half.
+/// Returns NaN when only one class is present or any score is NaN.
+pub fn roc_auc(scores: &[f64], labels: &[bool]) -> f64 {
+    assert_eq!(scores.len(), labels.len(), "roc_auc: one label per score");
+    if scores.iter().any(|s| s.is_nan()) {
+        return f64::NAN;
+    }
+
+    let mut order = (0..scores.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| scores[a].total_cmp(&scores[b]));
+
+    // Sum of the ranks of the positives, tied scores sharing their mean rank.
+    let mut rank_sum = 0.0;
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start;
+        while end < order.len() && scores[order[end]].total_cmp(&scores[order[start]]).is_eq() {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        rank_sum += rank * order[start..end].iter().filter(|&&i| labels[i]).count() as f64;
+        start = end;
+    }
+
+    let positives = labels.iter().filter(|&&l| l).count() as f64;
+    let negatives = labels.len() as f64 - positives;
+    (rank_sum - positives * (positives + 1.0) / 2.0) / (positives * negatives)
+}
+
+/// Mean negative log probability of the targets, clipped so a confident
+/// wrong answer costs `-ln(1e-15)` instead of infinity.
+pub fn log_loss(probabilities: &[Vec<f64>], targets: &[usize]) -> f64 {
+    assert_eq!(probabilities.len(), targets.len(), "log_loss: one target per sample");
+    assert!(!targets.is_empty(), "log_loss: empty batch");
+    const EPS: f64 = 1e-15;
+
+    zip(probabilities, targets)
+        .map(|(p, &target)| -p[target].clamp(EPS, 1.0 - EPS).ln())
+        .sum::<f64>()
+        / targets.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    #[test]
+    fn test_confusion_matrix() {
+        let actual = [0, 0, 0, 1, 1, 2, 2, 2, 2, 2];
+        let predicted = [0, 0, 1, 1, 2, 2, 2, 2, 0, 1];
+        let matrix = ConfusionMatrix::from_labels(3, &actual, &predicted);
+
+        assert_eq!(matrix.total(), 10);
+        assert_eq!(matrix.get(2, 0), 1);
+        assert_eq!(matrix.support(2), 5);
+        assert!(close(matrix.accuracy(), 0.6));
+        assert!(close(matrix.precision(0), 2.0 / 3.0));
+        assert!(close(matrix.recall(2), 3.0 / 5.0));
+        assert!(close(matrix.f1(1), 2.0 * (1.0 / 3.0) * 0.5 / (1.0 / 3.0 + 0.5)));
+
+        let macro_recall = (2.0 / 3.0 + 0.5 + 0.6) / 3.0;
+        assert!(close(matrix.recall_avg(Average::Macro), macro_recall));
+        // Weighted recall is the accuracy.
+        assert!(close(matrix.recall_avg(Average::Weighted), 0.6));
+        assert!(close(matrix.f1_avg(Average::Micro), 0.6));
+        assert!(close(matrix.precision_avg(Average::Micro), 0.6));
+
+        let empty = ConfusionMatrix::new(2);
+        assert_eq!((empty.accuracy(), empty.precision(0), empty.f1_avg(Average::Weighted)), (0.0, 0.0, 0.0));
+        assert_eq!(ConfusionMatrix::new(0).f1_avg(Average::Macro), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "class out of range: actual 0 predicted 5 with 3 classes")]
+    fn test_get_out_of_range() {
+        let matrix = ConfusionMatrix::from_labels(3, &[1], &[2]);
+        matrix.get(0, 5);
+    }
+
+    #[test]
+    fn test_merge_and_display() {
+        let actual = [0, 1, 1, 2, 0, 2];
+        let predicted = [0, 1, 0, 2, 2, 2];
+        let mut merged = ConfusionMatrix::from_labels(3, &actual[..3], &predicted[..3]);
+        merged.merge(&ConfusionMatrix::from_labels(3, &actual[3..], &predicted[3..]));
+        assert_eq!(merged, ConfusionMatrix::from_labels(3, &actual, &predicted));
+
+        let text = merged.to_string();
+        assert_eq!(text.lines().count(), 5);
+        assert!(text.lines().nth(3).unwrap().starts_with("   2 |    0    0    2 | 0.6667 1.0000 0.8000 2"));
+        assert!(text.contains("accuracy 0.6667"));
+
+        merged.reset();
+        assert_eq!(merged.total(), 0);
+    }
+
+    #[test]
+    fn test_top_k_accuracy() {
+        let scores = vec![
+            vec![0.1, 0.7, 0.2],
+            vec![0.5, 0.3, 0.2],
+            vec![0.2, 0.2, 0.6],
+        ];
+        let targets = [1, 1, 0];
+        assert!(close(top_k_accuracy(&scores, &targets, 1), 1.0 / 3.0));
+        assert!(close(top_k_accuracy(&scores, &targets, 2), 2.0 / 3.0));
+        assert!(close(top_k_accuracy(&scores, &targets, 3), 1.0));
+
+        let diverged = vec![vec![f64::NAN, 0.9, 0.8]];
+        assert_eq!(top_k_accuracy(&diverged, &[0], 1), 0.0);
+    }
+
+    #[test]
+    fn test_roc_auc() {
+        assert!(close(roc_auc(&[0.1, 0.4, 0.35, 0.8], &[false, false, true, true]), 0.75));
+        assert!(close(roc_auc(&[0.9, 0.8, 0.1], &[true, true, false]), 1.0));
+        assert!(close(roc_auc(&[0.5, 0.5, 0.5, 0.5], &[true, false, true, false]), 0.5));
+        assert!(roc_auc(&[0.2, 0.3], &[true, true]).is_nan());
+        assert!(roc_auc(&[0.2, f64::NAN, 0.7], &[true, false, true]).is_nan());
+    }
+
+    #[test]
+    fn test_log_loss() {
+        let probs = vec![vec![0.9, 0.1], vec![0.2, 0.8], vec![1.0, 0.0]];
+        let expected = -(0.9f64.ln() + 0.8f64.ln() + 1e-15f64.ln()) / 3.0;
+        assert!(close(log_loss(&probs, &[0, 1, 1]), expected));
+    }
+
+    #[test]
+    #[should_panic(expected = "empty batch")]
+    fn test_log_loss_empty() {
+        log_loss(&[], &[]);
+    }
+}
@@ -1,30 +1,79 @@
 use std::iter::zip;
 
+use rand::Rng;
+
+use crate::Activation;
+use crate::Init;
+use crate::Tape;
+use crate::Tensor;
+use crate::Var;
+use crate::with_rng;
 use crate::Value;
-use crate::create_random_floats;
-use crate::he_initialization;
+
+/// Common interface of everything that has parameters, so optimizers,
+/// serializers and trainers can work on any model.
+pub trait Module {
+    fn forward(&self, input: Vec<Value>) -> Vec<Value>;
+
+    /// `forward` for every sample of a minibatch, e.g. to feed a batch loss.
+    fn forward_batch(&self, inputs: &[Vec<Value>]) -> Vec<Vec<Value>> {
+        inputs.iter().map(|input| self.forward(input.clone())).collect()
+    }
+
+    fn parameters(&self) -> Vec<&Value>;
+
+    /// Parameters with stable dotted names, e.g. `layers.0.weight.3.1` for the
+    /// weight from input 1 to neuron 3 of the first layer.
+    fn named_parameters(&self) -> Vec<(String, &Value)>;
+
+    fn zero_grad(&self) {
+        for p in self.parameters() {
+            p.zero_grad();
+        }
+    }
+
+    fn set_training(&mut self, training: bool);
+
+    fn is_training(&self) -> bool;
+
+    fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    fn eval(&mut self) {
+        self.set_training(false);
+    }
+}
+
+fn prefixed<'a>(prefix: &str, named: Vec<(String, &'a Value)>) -> Vec<(String, &'a Value)> {
+    named.into_iter()
+        .map(|(name, p)| (format!("{}.{}", prefix, name), p))
+        .collect()
+}
 
 #[derive(Debug)]
 pub struct Neuron {
     weights: Vec<Value>,
     bias: Value,
-    nonlin: bool
+    activation: Activation,
+    training: bool,
 }
 
 impl Neuron {
-    pub fn new(input_size: usize, nonlin: bool) -> Neuron {
-        let weights = if nonlin {
-            he_initialization(input_size, input_size)
-                .iter().map(|&x| Value::new(x)).collect()
-        } else {
-            create_random_floats(input_size).iter().map(|&x| Value::new(x)).collect()
-        };
-        let bias = Value::new(0.0);
+    /// A standalone neuron with the default init for `activation`. It has a
+    /// single output, so schemes using the fan-out (Xavier) see 1, unlike the
+    /// neurons of `Layer::new` which are drawn with the layer's output size.
+    pub fn new(input_size: usize, activation: Activation) -> Neuron {
+        let weights = with_rng(|rng| Init::default_for(activation).weights(input_size, 1, rng)).remove(0);
+        Neuron::from_weights(&weights, 0.0, activation)
+    }
 
+    pub fn from_weights(weights: &[f64], bias: f64, activation: Activation) -> Neuron {
         Neuron {
-            weights,
-            bias,
-            nonlin,
+            weights: weights.iter().map(|&x| Value::new(x)).collect(),
+            bias: Value::new(bias),
+            activation,
+            training: true,
         }
     }
 
@@ -36,34 +85,96 @@ impl Neuron {
         &self.bias
     }
 
+    pub fn activation(&self) -> Activation {
+        self.activation
+    }
+
     pub fn forward(&self, input: &[Value]) -> Value {
         let o = zip(self.weights.iter(), input.iter())
-            .map(|(w, i)| w.mul(i))
+            .map(|(w, i)| w * i)
             .sum::<Value>()
-            .add(&self.bias);
+            + &self.bias;
         
-        if self.nonlin {
-            o.relu()
-        } else {
-            o
-        }
+        self.activation.apply(&o)
+    }
+
+    /// Same as `forward` on plain floats, without building a graph.
+    pub fn forward_f64(&self, input: &[f64]) -> f64 {
+        let o = zip(self.weights.iter(), input.iter())
+            .fold(0.0, |acc, (w, i)| acc + w.data() * i)
+            + self.bias.data();
+
+        self.activation.apply_f64(o)
+    }
+}
+
+impl Module for Neuron {
+    fn forward(&self, input: Vec<Value>) -> Vec<Value> {
+        vec![Neuron::forward(self, &input)]
+    }
+
+    fn parameters(&self) -> Vec<&Value> {
+        let mut params = Vec::with_capacity(self.weights.len() + 1);
+        params.push(&self.bias);
+        params.extend(self.weights.iter());
+        params
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Value)> {
+        let mut params = Vec::with_capacity(self.weights.len() + 1);
+        params.push(("bias".to_string(), &self.bias));
+        params.extend(self.weights.iter().enumerate().map(|(i, w)| (format!("weight.{}", i), w)));
+        params
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
     }
 }
 
 #[derive(Debug)]
 pub struct Layer {
     neurons: Vec<Neuron>,
+    training: bool,
 }
 
 impl Layer {
-    pub fn new(input_size: usize, output_size: usize, nonlin: bool) -> Layer {
-        let mut neurons = Vec::with_capacity(output_size);
-        for _ in 0..output_size {
-            neurons.push(Neuron::new(input_size, nonlin));
+    pub fn new(input_size: usize, output_size: usize, activation: Activation) -> Layer {
+        with_rng(|rng| Layer::with_init(input_size, output_size, activation, Init::default_for(activation), rng))
+    }
+
+    pub fn with_init<R: Rng + ?Sized>(
+        input_size: usize,
+        output_size: usize,
+        activation: Activation,
+        init: Init,
+        rng: &mut R,
+    ) -> Layer {
+        let neurons = init.weights(input_size, output_size, rng)
+            .iter()
+            .map(|weights| Neuron::from_weights(weights, 0.0, activation))
+            .collect();
+
+        Layer::from_neurons(neurons)
+    }
+
+    /// The neurons must share one activation and input size, the batched
+    /// forward passes and the exporters treat the layer as one matrix.
+    pub fn from_neurons(neurons: Vec<Neuron>) -> Layer {
+        if let Some(first) = neurons.first() {
+            for (i, n) in neurons.iter().enumerate() {
+                assert_eq!(n.activation, first.activation, "from_neurons: neuron {} has a different activation", i);
+                assert_eq!(n.weights.len(), first.weights.len(), "from_neurons: neuron {} has a different input size", i);
+            }
         }
 
         Layer {
             neurons,
+            training: true,
         }
     }
 
@@ -71,28 +182,127 @@ impl Layer {
         &self.neurons
     }
 
-    pub fn forward(&self, input: Vec<Value>) -> Vec<Value> {
+    pub fn activation(&self) -> Activation {
+        self.neurons.first().map_or(Activation::Identity, |n| n.activation)
+    }
+
+    /// Weights as a `[output_size, input_size]` tensor sharing the parameters.
+    pub fn weight_tensor(&self) -> Tensor {
+        let input_size = self.neurons.first().map_or(0, |n| n.weights.len());
+        let weights = self.neurons.iter()
+            .flat_map(|n| n.weights.iter().cloned())
+            .collect();
+        Tensor::new(weights, &[self.neurons.len(), input_size])
+    }
+
+    pub fn bias_tensor(&self) -> Tensor {
+        let biases = self.neurons.iter().map(|n| n.bias.clone()).collect();
+        Tensor::new(biases, &[self.neurons.len()])
+    }
+
+    /// Forward pass for a `[batch, input_size]` tensor.
+    pub fn forward_tensor(&self, input: &Tensor) -> Tensor {
+        let o = input.matmul(&self.weight_tensor().t()).add(&self.bias_tensor());
+        let activation = self.activation();
+
+        o.map(|v| activation.apply(v))
+    }
+
+    pub fn forward_f64(&self, input: &[f64]) -> Vec<f64> {
+        self.neurons.iter()
+            .map(|n| n.forward_f64(input))
+            .collect()
+    }
+
+    /// Records the layer on `tape` for a `[batch, input_size]` input.
+    pub fn forward_tape(&self, tape: &mut Tape, input: Var) -> Var {
+        let weights = self.weight_tensor();
+        let w = tape.param(weights.values(), weights.shape()[0], weights.shape()[1]);
+        let b = tape.param(self.bias_tensor().values(), 1, self.neurons.len());
+        let o = tape.linear(input, w, b);
+
+        tape.activation(o, self.activation())
+    }
+}
+
+impl Module for Layer {
+    fn forward(&self, input: Vec<Value>) -> Vec<Value> {
         self.neurons.iter()
             .map(|n| n.forward(&input))
             .collect()
     }
+
+    fn parameters(&self) -> Vec<&Value> {
+        self.neurons.iter().flat_map(|n| n.parameters()).collect()
+    }
+
+    // Named like a PyTorch `Linear`, a `[out, in]` weight and an `[out]` bias,
+    // rather than by neuron.
+    fn named_parameters(&self) -> Vec<(String, &Value)> {
+        self.neurons.iter()
+            .enumerate()
+            .flat_map(|(o, n)| {
+                n.named_parameters()
+                    .into_iter()
+                    .map(move |(name, p)| match name.split_once('.') {
+                        Some((kind, i)) => (format!("{}.{}.{}", kind, o, i), p),
+                        None => (format!("{}.{}", name, o), p),
+                    })
+            })
+            .collect()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        for neuron in self.neurons.iter_mut() {
+            neuron.set_training(training);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
 }
 
 #[derive(Debug)]
 pub struct MLP {
     layers: Vec<Layer>,
+    training: bool,
 }
 
 impl MLP {
+    /// ReLU on the hidden layers and no activation on the output layer.
     pub fn new(layer_dims: &[usize]) -> MLP {
-        println!("layer_dims: {}", layer_dims.len());
-        let mut layers = Vec::with_capacity(layer_dims.len() - 1);
-        for i in 0..layer_dims.len() - 1 {
-            layers.push(Layer::new(layer_dims[i], layer_dims[i+1], i != layer_dims.len() - 2));
+        with_rng(|rng| MLP::with_rng(layer_dims, rng))
+    }
+
+    /// Like `new`, but draws the initial weights from `rng` so a seeded rng
+    /// gives the same model every time.
+    pub fn with_rng<R: Rng + ?Sized>(layer_dims: &[usize], rng: &mut R) -> MLP {
+        let mut builder = MLP::builder(layer_dims[0]);
+        for i in 1..layer_dims.len() {
+            let activation = if i == layer_dims.len() - 1 {
+                Activation::Identity
+            } else {
+                Activation::Relu
+            };
+            builder = builder.layer(layer_dims[i], activation);
         }
 
+        builder.build_with_rng(rng)
+    }
+
+    pub fn from_layers(layers: Vec<Layer>) -> MLP {
         MLP {
             layers,
+            training: true,
+        }
+    }
+
+    pub fn builder(input_size: usize) -> MlpBuilder {
+        MlpBuilder {
+            input_size,
+            layers: vec![],
         }
     }
 
@@ -100,40 +310,104 @@ impl MLP {
         &self.layers
     }
 
-    pub fn forward(&self, input: Vec<Value>) -> Vec<Value> {
-        let mut new_x = input;
+    pub fn forward_tensor(&self, input: &Tensor) -> Tensor {
+        let mut new_x = input.clone();
 
         for layer in self.layers.iter() {
-            new_x = layer.forward(new_x);
+            new_x = layer.forward_tensor(&new_x);
         }
 
         new_x
     }
 
-    pub fn parameters(&self) -> Vec<&Value> {
-        let mut params = Vec::new();
+    /// Inference on plain floats, gives exactly the data of `forward` without
+    /// allocating any graph nodes.
+    pub fn forward_f64(&self, input: &[f64]) -> Vec<f64> {
+        let mut new_x = input.to_vec();
 
         for layer in self.layers.iter() {
-            for neuron in layer.neurons.iter() {
-                params.push(neuron.bias());
-                for weight in neuron.weights().iter() {
-                    params.push(weight);
-                }
-            }
+            new_x = layer.forward_f64(&new_x);
         }
 
-        params
+        new_x
     }
 
-    pub fn zero_grad(&self) {
+    pub fn forward_tape(&self, tape: &mut Tape, input: Var) -> Var {
+        let mut new_x = input;
+
         for layer in self.layers.iter() {
-            for neuron in layer.neurons.iter() {
-                neuron.bias().zero_grad();
-                for weight in neuron.weights().iter() {
-                    weight.zero_grad();
-                }
-            }
+            new_x = layer.forward_tape(tape, new_x);
+        }
+
+        new_x
+    }
+}
+
+/// Builds an `MLP` layer by layer with a separate activation for each layer.
+#[derive(Debug, Clone)]
+pub struct MlpBuilder {
+    input_size: usize,
+    layers: Vec<(usize, Activation, Init)>,
+}
+
+impl MlpBuilder {
+    /// Adds a layer initialized with `Init::default_for(activation)`.
+    pub fn layer(self, output_size: usize, activation: Activation) -> MlpBuilder {
+        self.layer_with_init(output_size, activation, Init::default_for(activation))
+    }
+
+    pub fn layer_with_init(mut self, output_size: usize, activation: Activation, init: Init) -> MlpBuilder {
+        self.layers.push((output_size, activation, init));
+        self
+    }
+
+    pub fn build(self) -> MLP {
+        with_rng(|rng| self.build_with_rng(rng))
+    }
+
+    pub fn build_with_rng<R: Rng + ?Sized>(self, rng: &mut R) -> MLP {
+        let mut layers = Vec::with_capacity(self.layers.len());
+        let mut input_size = self.input_size;
+        for (output_size, activation, init) in self.layers {
+            layers.push(Layer::with_init(input_size, output_size, activation, init, rng));
+            input_size = output_size;
         }
+
+        MLP::from_layers(layers)
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, input: Vec<Value>) -> Vec<Value> {
+        let mut new_x = input;
+
+        for layer in self.layers.iter() {
+            new_x = layer.forward(new_x);
+        }
+
+        new_x
+    }
+
+    fn parameters(&self) -> Vec<&Value> {
+        self.layers.iter().flat_map(|l| l.parameters()).collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Value)> {
+        self.layers.iter()
+            .enumerate()
+            .flat_map(|(i, l)| prefixed(&format!("layers.{}", i), l.named_parameters()))
+            .collect()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        for layer in self.layers.iter_mut() {
+            layer.set_training(training);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
     }
 }
 
@@ -152,16 +426,166 @@ mod tests {
         let input = vec![Value::new(1.0)];
         let output = mlp.forward(input);
         let output_softmax = softmax(&output);
-        println!("{:#?}", output[0]);
         let label = one_hot_encode(0, 1);
         let loss = cross_entropy_loss(&output_softmax, &label);
-        //    &vec![Value::new(1.0)]);
         loss.backward();
 
+        assert_eq!(output.len(), 1);
+        assert!(loss.data().is_finite());
+    }
+
+    #[test]
+    fn test_forward_tensor_matches_forward() {
+        let mlp = MLP::new(&[3, 4, 2]);
+        let samples = [[0.5, -1.0, 2.0], [0.1, 0.2, 0.3]];
 
-        println!("LOSS: {:#?}", loss);
-        println!("{:#?}", output[0]);
-        println!("{:#?}", mlp);
-        println!("{:#?}", mlp.parameters());
+        let batch = Tensor::from_f64(&samples.concat(), &[2, 3]);
+        let out = mlp.forward_tensor(&batch);
+        assert_eq!(out.shape(), &[2, 2]);
+
+        for (i, sample) in samples.iter().enumerate() {
+            let expected = mlp.forward(sample.iter().map(|&x| Value::new(x)).collect());
+            for (j, e) in expected.iter().enumerate() {
+                assert!((out.get(&[i, j]).data() - e.data()).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_forward_f64_matches_forward() {
+        let mlp = MLP::builder(3)
+            .layer(6, Activation::Gelu)
+            .layer(5, Activation::LeakyRelu(0.1))
+            .layer(5, Activation::Elu(0.5))
+            .layer(4, Activation::Silu)
+            .layer(4, Activation::Softplus)
+            .layer(3, Activation::Tanh)
+            .layer(3, Activation::Sigmoid)
+            .layer(2, Activation::Identity)
+            .build();
+
+        for sample in [[0.5, -1.0, 2.0], [-3.0, 0.2, 0.0], [10.0, -7.5, 0.3]] {
+            let expected = mlp.forward(sample.iter().map(|&x| Value::new(x)).collect());
+            let out = mlp.forward_f64(&sample);
+            assert_eq!(
+                out.iter().map(|x| x.to_bits()).collect::<Vec<_>>(),
+                expected.iter().map(|v| v.data().to_bits()).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "neuron 1 has a different activation")]
+    fn test_from_neurons_mixed_activations() {
+        Layer::from_neurons(vec![
+            Neuron::from_weights(&[1.0, 2.0], 0.0, Activation::Relu),
+            Neuron::from_weights(&[1.0, 2.0], 0.0, Activation::Tanh),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "neuron 1 has a different input size")]
+    fn test_from_neurons_mixed_input_sizes() {
+        Layer::from_neurons(vec![
+            Neuron::from_weights(&[1.0, 2.0], 0.0, Activation::Relu),
+            Neuron::from_weights(&[1.0], 0.0, Activation::Relu),
+        ]);
+    }
+
+    #[test]
+    fn test_forward_batch() {
+        let mlp = MLP::new(&[2, 3, 2]);
+        let samples = vec![
+            vec![Value::new(0.5), Value::new(-1.0)],
+            vec![Value::new(2.0), Value::new(0.1)],
+        ];
+
+        let outputs = mlp.forward_batch(&samples);
+        assert_eq!(outputs.len(), 2);
+        for (sample, out) in samples.iter().zip(outputs.iter()) {
+            let expected = mlp.forward(sample.clone());
+            assert_eq!(
+                out.iter().map(|v| v.data()).collect::<Vec<_>>(),
+                expected.iter().map(|v| v.data()).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_named_parameters() {
+        let mut mlp = MLP::new(&[3, 2, 1]);
+        let named = mlp.named_parameters();
+        assert_eq!(named.len(), mlp.parameters().len());
+        assert_eq!(named.len(), 2 * 3 + 2 + 2 + 1);
+        assert_eq!(named[0].0, "layers.0.bias.0");
+        assert_eq!(named[1].0, "layers.0.weight.0.0");
+        assert_eq!(named[6].0, "layers.0.weight.1.1");
+        assert_eq!(named.last().unwrap().0, "layers.1.weight.0.1");
+        assert!(std::ptr::eq(named[6].1, &mlp.layers()[0].neurons()[1].weights()[1]));
+
+        mlp.eval();
+        assert!(!mlp.is_training());
+        assert!(mlp.layers().iter().all(|l| !l.is_training()));
+        mlp.train();
+        assert!(mlp.layers()[1].neurons()[0].is_training());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_zero_grad() {
+        let mlp = MLP::new(&[2, 2]);
+        let out = mlp.forward(vec![Value::new(1.0), Value::new(2.0)]);
+        out.iter().sum::<Value>().backward();
+        assert!(mlp.parameters().iter().any(|p| p.grad() != 0.0));
+
+        mlp.zero_grad();
+        assert!(mlp.parameters().iter().all(|p| p.grad() == 0.0));
+    }
+
+    #[test]
+    fn test_builder_activations() {
+        let mlp = MLP::builder(2)
+            .layer(4, Activation::Tanh)
+            .layer(3, Activation::LeakyRelu(0.1))
+            .layer(1, Activation::Sigmoid)
+            .build();
+
+        let activations = mlp.layers().iter().map(|l| l.activation()).collect::<Vec<_>>();
+        assert_eq!(activations, vec![Activation::Tanh, Activation::LeakyRelu(0.1), Activation::Sigmoid]);
+        assert_eq!(mlp.layers()[1].neurons()[0].weights().len(), 4);
+
+        let out = mlp.forward(vec![Value::new(0.3), Value::new(-0.8)]);
+        assert!(out[0].data() > 0.0 && out[0].data() < 1.0);
+
+        let default = MLP::new(&[2, 3, 2]);
+        assert_eq!(default.layers()[0].activation(), Activation::Relu);
+        assert_eq!(default.layers()[1].activation(), Activation::Identity);
+    }
+
+    #[test]
+    fn test_seeded_init() {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        let a = MLP::with_rng(&[4, 8, 3], &mut StdRng::seed_from_u64(42));
+        let b = MLP::with_rng(&[4, 8, 3], &mut StdRng::seed_from_u64(42));
+        let c = MLP::with_rng(&[4, 8, 3], &mut StdRng::seed_from_u64(43));
+
+        let data = |m: &MLP| m.parameters().iter().map(|p| p.data()).collect::<Vec<_>>();
+        assert_eq!(data(&a), data(&b));
+        assert_ne!(data(&a), data(&c));
+
+        // The output layer is initialized symmetrically around zero.
+        let output = a.layers()[1].weight_tensor().data();
+        assert!(output.iter().any(|&w| w < 0.0) && output.iter().any(|&w| w > 0.0));
+    }
+
+    #[test]
+    fn test_layer_with_init() {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        let layer = Layer::with_init(3, 2, Activation::Tanh, Init::Constant(0.5), &mut StdRng::seed_from_u64(0));
+        assert_eq!(layer.weight_tensor().data(), vec![0.5; 6]);
+        assert_eq!(layer.bias_tensor().data(), vec![0.0; 2]);
+    }
+}
@@ -0,0 +1,856 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::Activation;
+use crate::ModelError;
+use crate::MLP;
+
+const IR_VERSION: i64 = 8;
+const OPSET_VERSION: i64 = 13;
+// TensorProto.DataType.FLOAT
+const ELEM_FLOAT: i64 = 1;
+// AttributeProto.AttributeType
+const ATTR_FLOAT: i64 = 1;
+const ATTR_INT: i64 = 2;
+const ATTR_STRING: i64 = 3;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+/// Minimal protobuf encoder, just the wire types ONNX needs.
+#[derive(Default)]
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire: u8) {
+        self.varint(((field as u64) << 3) | wire as u64);
+    }
+
+    fn int(&mut self, field: u32, v: i64) {
+        self.key(field, WIRE_VARINT);
+        self.varint(v as u64);
+    }
+
+    fn float(&mut self, field: u32, v: f32) {
+        self.key(field, WIRE_FIXED32);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, WIRE_BYTES);
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, field: u32, s: &str) {
+        self.bytes(field, s.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: ProtoWriter) {
+        self.bytes(field, &message.buf);
+    }
+}
+
+enum Field<'a> {
+    Varint(u64),
+    // Only skipped, none of the fields read here are 64 bit fixed.
+    Fixed64,
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Field<'a> {
+    fn int(&self) -> Result<i64, ModelError> {
+        match self {
+            Field::Varint(v) => Ok(*v as i64),
+            _ => Err(ModelError::InvalidFormat("expected a varint field".to_string())),
+        }
+    }
+
+    fn bytes(&self) -> Result<&'a [u8], ModelError> {
+        match self {
+            Field::Bytes(b) => Ok(b),
+            _ => Err(ModelError::InvalidFormat("expected a length delimited field".to_string())),
+        }
+    }
+
+    fn string(&self) -> Result<String, ModelError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|e| ModelError::InvalidFormat(e.to_string()))
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, ModelError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or_else(|| ModelError::InvalidFormat("truncated varint".to_string()))?;
+        *pos += 1;
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(ModelError::InvalidFormat("varint too long".to_string()))
+}
+
+fn read_fields(bytes: &[u8]) -> Result<Vec<(u32, Field<'_>)>, ModelError> {
+    let truncated = || ModelError::InvalidFormat("truncated field".to_string());
+    let mut fields = vec![];
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let key = read_varint(bytes, &mut pos)?;
+        let field = (key >> 3) as u32;
+        let value = match (key & 7) as u8 {
+            WIRE_VARINT => Field::Varint(read_varint(bytes, &mut pos)?),
+            WIRE_FIXED64 => {
+                let end = pos.checked_add(8).ok_or_else(truncated)?;
+                bytes.get(pos..end).ok_or_else(truncated)?;
+                pos += 8;
+                Field::Fixed64
+            },
+            WIRE_BYTES => {
+                let len = read_varint(bytes, &mut pos)? as usize;
+                let end = pos.checked_add(len).ok_or_else(truncated)?;
+                let raw = bytes.get(pos..end).ok_or_else(truncated)?;
+                pos += len;
+                Field::Bytes(raw)
+            },
+            WIRE_FIXED32 => {
+                let end = pos.checked_add(4).ok_or_else(truncated)?;
+                let raw = bytes.get(pos..end).ok_or_else(truncated)?;
+                pos += 4;
+                Field::Fixed32(u32::from_le_bytes(raw.try_into().unwrap()))
+            },
+            wire => return Err(ModelError::InvalidFormat(format!("unsupported wire type {}", wire))),
+        };
+        fields.push((field, value));
+    }
+
+    Ok(fields)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnnxAttribute {
+    Float(f32),
+    Int(i64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxNode {
+    pub name: String,
+    pub op_type: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub attributes: Vec<(String, OnnxAttribute)>,
+}
+
+impl OnnxNode {
+    fn new(name: String, op_type: &str, inputs: &[&str], output: &str) -> OnnxNode {
+        OnnxNode {
+            name,
+            op_type: op_type.to_string(),
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: vec![output.to_string()],
+            attributes: vec![],
+        }
+    }
+
+    fn attribute(mut self, name: &str, value: OnnxAttribute) -> OnnxNode {
+        self.attributes.push((name.to_string(), value));
+        self
+    }
+
+    fn float_attribute(&self, name: &str, default: f64) -> f64 {
+        match self.attributes.iter().find(|(n, _)| n == name) {
+            Some((_, OnnxAttribute::Float(v))) => *v as f64,
+            _ => default,
+        }
+    }
+
+    fn int_attribute(&self, name: &str, default: i64) -> i64 {
+        match self.attributes.iter().find(|(n, _)| n == name) {
+            Some((_, OnnxAttribute::Int(v))) => *v,
+            _ => default,
+        }
+    }
+}
+
+/// A float tensor, stored row-major as `f64` in memory and `f32` on disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxTensor {
+    pub name: String,
+    pub dims: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+/// The parts of an ONNX model written by `OnnxExport`, enough to check an
+/// export and evaluate it with `run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxGraph {
+    pub name: String,
+    pub opset: i64,
+    pub nodes: Vec<OnnxNode>,
+    pub initializers: Vec<OnnxTensor>,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+/// Exports an `MLP` as an ONNX graph of `Gemm` and activation nodes, with
+/// an optional trailing `Softmax`. The input is a `[batch, input_size]`
+/// float tensor called `input` and the output is called `output`.
+pub struct OnnxExport<'a> {
+    mlp: &'a MLP,
+    softmax: bool,
+}
+
+impl<'a> OnnxExport<'a> {
+    pub fn new(mlp: &'a MLP) -> OnnxExport<'a> {
+        OnnxExport {
+            mlp,
+            softmax: false,
+        }
+    }
+
+    /// Ends the graph with a softmax over the outputs.
+    pub fn softmax(mut self, softmax: bool) -> OnnxExport<'a> {
+        self.softmax = softmax;
+        self
+    }
+
+    pub fn graph(&self) -> OnnxGraph {
+        let mut graph = OnnxGraph {
+            name: "microml_mlp".to_string(),
+            opset: OPSET_VERSION,
+            nodes: vec![],
+            initializers: vec![],
+            inputs: vec!["input".to_string()],
+            outputs: vec!["output".to_string()],
+        };
+
+        let mut x = "input".to_string();
+        for (l, layer) in self.mlp.layers().iter().enumerate() {
+            let prefix = format!("layers.{}", l);
+            let neurons = layer.neurons();
+            let input_size = neurons.first().map_or(0, |n| n.weights().len());
+
+            graph.initializers.push(OnnxTensor {
+                name: format!("{}.weight", prefix),
+                dims: vec![neurons.len(), input_size],
+                data: neurons.iter().flat_map(|n| n.weights().iter().map(|w| w.data())).collect(),
+            });
+            graph.initializers.push(OnnxTensor {
+                name: format!("{}.bias", prefix),
+                dims: vec![neurons.len()],
+                data: neurons.iter().map(|n| n.bias().data()).collect(),
+            });
+
+            let out = format!("{}.gemm", prefix);
+            let weight = format!("{}.weight", prefix);
+            let bias = format!("{}.bias", prefix);
+            graph.nodes.push(
+                OnnxNode::new(out.clone(), "Gemm", &[&x, &weight, &bias], &out)
+                    .attribute("transB", OnnxAttribute::Int(1))
+            );
+
+            x = graph.activation(layer.activation(), &out, &prefix);
+        }
+
+        if self.softmax {
+            graph.nodes.push(
+                OnnxNode::new("softmax".to_string(), "Softmax", &[&x], "softmax")
+                    .attribute("axis", OnnxAttribute::Int(-1))
+            );
+            x = "softmax".to_string();
+        }
+        graph.nodes.push(OnnxNode::new("output".to_string(), "Identity", &[&x], "output"));
+
+        graph
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let input_size = self.mlp.layers().first()
+            .and_then(|l| l.neurons().first())
+            .map_or(0, |n| n.weights().len());
+        let output_size = self.mlp.layers().last().map_or(input_size, |l| l.neurons().len());
+
+        self.graph().encode(input_size, output_size)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ModelError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+fn value_info(name: &str, size: usize) -> ProtoWriter {
+    let mut batch = ProtoWriter::default();
+    batch.string(2, "batch");
+    let mut features = ProtoWriter::default();
+    features.int(1, size as i64);
+
+    let mut shape = ProtoWriter::default();
+    shape.message(1, batch);
+    shape.message(1, features);
+    let mut tensor_type = ProtoWriter::default();
+    tensor_type.int(1, ELEM_FLOAT);
+    tensor_type.message(2, shape);
+    let mut type_proto = ProtoWriter::default();
+    type_proto.message(1, tensor_type);
+
+    let mut info = ProtoWriter::default();
+    info.string(1, name);
+    info.message(2, type_proto);
+    info
+}
+
+impl OnnxGraph {
+    fn scalar(&mut self, name: String, value: f64) -> String {
+        self.initializers.push(OnnxTensor {
+            name: name.clone(),
+            dims: vec![],
+            data: vec![value],
+        });
+        name
+    }
+
+    fn op(&mut self, op_type: &str, inputs: &[&str], output: String) -> String {
+        self.nodes.push(OnnxNode::new(output.clone(), op_type, inputs, &output));
+        output
+    }
+
+    /// Appends the nodes for `activation` applied to `x` and returns the
+    /// name of the result. SiLU and GELU have no opset 13 operator and are
+    /// built from elementwise nodes.
+    fn activation(&mut self, activation: Activation, x: &str, prefix: &str) -> String {
+        let name = |suffix: &str| format!("{}.{}", prefix, suffix);
+        match activation {
+            Activation::Identity => x.to_string(),
+            Activation::Relu => self.op("Relu", &[x], name("relu")),
+            Activation::LeakyRelu(slope) => {
+                self.nodes.push(
+                    OnnxNode::new(name("leaky_relu"), "LeakyRelu", &[x], &name("leaky_relu"))
+                        .attribute("alpha", OnnxAttribute::Float(slope as f32))
+                );
+                name("leaky_relu")
+            },
+            Activation::Elu(alpha) => {
+                self.nodes.push(
+                    OnnxNode::new(name("elu"), "Elu", &[x], &name("elu"))
+                        .attribute("alpha", OnnxAttribute::Float(alpha as f32))
+                );
+                name("elu")
+            },
+            Activation::Gelu => {
+                let c = self.scalar(name("gelu.c"), 0.044715);
+                let k = self.scalar(name("gelu.k"), (2.0 / std::f64::consts::PI).sqrt());
+                let one = self.scalar(name("gelu.one"), 1.0);
+                let half = self.scalar(name("gelu.half"), 0.5);
+
+                let x2 = self.op("Mul", &[x, x], name("gelu.x2"));
+                let x3 = self.op("Mul", &[&x2, x], name("gelu.x3"));
+                let cx3 = self.op("Mul", &[&x3, &c], name("gelu.cx3"));
+                let inner = self.op("Add", &[x, &cx3], name("gelu.inner"));
+                let scaled = self.op("Mul", &[&inner, &k], name("gelu.scaled"));
+                let t = self.op("Tanh", &[&scaled], name("gelu.tanh"));
+                let t1 = self.op("Add", &[&t, &one], name("gelu.tanh1"));
+                let xt = self.op("Mul", &[x, &t1], name("gelu.xt"));
+                self.op("Mul", &[&xt, &half], name("gelu"))
+            },
+            Activation::Silu => {
+                let s = self.op("Sigmoid", &[x], name("silu.sigmoid"));
+                self.op("Mul", &[x, &s], name("silu"))
+            },
+            Activation::Tanh => self.op("Tanh", &[x], name("tanh")),
+            Activation::Sigmoid => self.op("Sigmoid", &[x], name("sigmoid")),
+            Activation::Softplus => self.op("Softplus", &[x], name("softplus")),
+        }
+    }
+
+    fn encode(&self, input_size: usize, output_size: usize) -> Vec<u8> {
+        let mut graph = ProtoWriter::default();
+        for node in &self.nodes {
+            let mut n = ProtoWriter::default();
+            for input in &node.inputs {
+                n.string(1, input);
+            }
+            for output in &node.outputs {
+                n.string(2, output);
+            }
+            n.string(3, &node.name);
+            n.string(4, &node.op_type);
+            for (name, value) in &node.attributes {
+                let mut a = ProtoWriter::default();
+                a.string(1, name);
+                match value {
+                    OnnxAttribute::Float(f) => {
+                        a.float(2, *f);
+                        a.int(20, ATTR_FLOAT);
+                    },
+                    OnnxAttribute::Int(i) => {
+                        a.int(3, *i);
+                        a.int(20, ATTR_INT);
+                    },
+                    OnnxAttribute::String(s) => {
+                        a.string(4, s);
+                        a.int(20, ATTR_STRING);
+                    },
+                }
+                n.message(5, a);
+            }
+            graph.message(1, n);
+        }
+        graph.string(2, &self.name);
+        for tensor in &self.initializers {
+            let mut t = ProtoWriter::default();
+            for &d in &tensor.dims {
+                t.int(1, d as i64);
+            }
+            t.int(2, ELEM_FLOAT);
+            t.string(8, &tensor.name);
+            let raw = tensor.data.iter().flat_map(|&x| (x as f32).to_le_bytes()).collect::<Vec<_>>();
+            t.bytes(9, &raw);
+            graph.message(5, t);
+        }
+        for input in &self.inputs {
+            graph.message(11, value_info(input, input_size));
+        }
+        for output in &self.outputs {
+            graph.message(12, value_info(output, output_size));
+        }
+
+        let mut opset = ProtoWriter::default();
+        opset.string(1, "");
+        opset.int(2, self.opset);
+
+        let mut model = ProtoWriter::default();
+        model.int(1, IR_VERSION);
+        model.string(2, "microml");
+        model.message(7, graph);
+        model.message(8, opset);
+        model.buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<OnnxGraph, ModelError> {
+        let mut graph = None;
+        let mut opset = 1;
+        for (field, value) in read_fields(bytes)? {
+            match field {
+                7 => graph = Some(value.bytes()?),
+                8 => {
+                    for (field, value) in read_fields(value.bytes()?)? {
+                        if field == 2 {
+                            opset = value.int()?;
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        let graph = graph.ok_or_else(|| ModelError::InvalidFormat("model has no graph".to_string()))?;
+
+        let mut out = OnnxGraph {
+            name: String::new(),
+            opset,
+            nodes: vec![],
+            initializers: vec![],
+            inputs: vec![],
+            outputs: vec![],
+        };
+        for (field, value) in read_fields(graph)? {
+            match field {
+                1 => out.nodes.push(decode_node(value.bytes()?)?),
+                2 => out.name = value.string()?,
+                5 => out.initializers.push(decode_tensor(value.bytes()?)?),
+                11 | 12 => {
+                    let name = read_fields(value.bytes()?)?
+                        .into_iter()
+                        .find(|(f, _)| *f == 1)
+                        .map(|(_, v)| v.string())
+                        .transpose()?
+                        .unwrap_or_default();
+                    if field == 11 { out.inputs.push(name) } else { out.outputs.push(name) }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(out)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<OnnxGraph, ModelError> {
+        OnnxGraph::from_bytes(&fs::read(path)?)
+    }
+
+    /// Evaluates the graph on a `[batch, input.len() / batch]` input and
+    /// returns the first output row-major.
+    pub fn run(&self, input: &[f64], batch: usize) -> Result<Vec<f64>, ModelError> {
+        let mut values: HashMap<&str, Array> = HashMap::new();
+        for t in &self.initializers {
+            values.insert(&t.name, Array { shape: t.dims.clone(), data: t.data.clone() });
+        }
+        let input_name = self.inputs.first()
+            .ok_or_else(|| ModelError::InvalidFormat("graph has no input".to_string()))?;
+        if batch == 0 || input.len() % batch != 0 {
+            return Err(ModelError::InvalidFormat(format!("input of length {} does not split into {} rows", input.len(), batch)));
+        }
+        values.insert(input_name, Array { shape: vec![batch, input.len() / batch], data: input.to_vec() });
+
+        for node in &self.nodes {
+            let args = node.inputs.iter()
+                .map(|name| {
+                    values.get(name.as_str())
+                        .ok_or_else(|| ModelError::MissingTensor(name.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let output = node.outputs.first()
+                .ok_or_else(|| ModelError::InvalidFormat(format!("{} node has no output", node.op_type)))?;
+            values.insert(output, eval(node, &args)?);
+        }
+
+        let output_name = self.outputs.first()
+            .ok_or_else(|| ModelError::InvalidFormat("graph has no output".to_string()))?;
+        values.remove(output_name.as_str())
+            .map(|a| a.data)
+            .ok_or_else(|| ModelError::MissingTensor(output_name.clone()))
+    }
+}
+
+fn decode_node(bytes: &[u8]) -> Result<OnnxNode, ModelError> {
+    let mut node = OnnxNode::new(String::new(), "", &[], "");
+    node.outputs.clear();
+    for (field, value) in read_fields(bytes)? {
+        match field {
+            1 => node.inputs.push(value.string()?),
+            2 => node.outputs.push(value.string()?),
+            3 => node.name = value.string()?,
+            4 => node.op_type = value.string()?,
+            5 => {
+                let mut name = String::new();
+                let mut attribute = None;
+                for (field, value) in read_fields(value.bytes()?)? {
+                    match (field, value) {
+                        (1, value) => name = value.string()?,
+                        (2, Field::Fixed32(bits)) => attribute = Some(OnnxAttribute::Float(f32::from_bits(bits))),
+                        (3, value) => attribute = Some(OnnxAttribute::Int(value.int()?)),
+                        (4, value) => attribute = Some(OnnxAttribute::String(value.string()?)),
+                        _ => {},
+                    }
+                }
+                if let Some(attribute) = attribute {
+                    node.attributes.push((name, attribute));
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(node)
+}
+
+fn dim(v: i64) -> Result<usize, ModelError> {
+    usize::try_from(v).map_err(|_| ModelError::InvalidFormat(format!("negative dimension {}", v)))
+}
+
+// Element count of `shape`, or None if it does not fit in a usize.
+fn numel(shape: &[usize]) -> Option<usize> {
+    shape.iter().try_fold(1usize, |acc, &d| acc.checked_mul(d))
+}
+
+fn decode_tensor(bytes: &[u8]) -> Result<OnnxTensor, ModelError> {
+    let mut tensor = OnnxTensor { name: String::new(), dims: vec![], data: vec![] };
+    let mut data_type = ELEM_FLOAT;
+    let mut raw: &[u8] = &[];
+    for (field, value) in read_fields(bytes)? {
+        match (field, value) {
+            (1, Field::Bytes(packed)) => {
+                let mut pos = 0;
+                while pos < packed.len() {
+                    tensor.dims.push(dim(read_varint(packed, &mut pos)? as i64)?);
+                }
+            },
+            (1, value) => tensor.dims.push(dim(value.int()?)?),
+            (2, value) => data_type = value.int()?,
+            (8, value) => tensor.name = value.string()?,
+            (9, value) => raw = value.bytes()?,
+            _ => {},
+        }
+    }
+    if data_type != ELEM_FLOAT {
+        return Err(ModelError::InvalidFormat(format!("unsupported data type {} for {}", data_type, tensor.name)));
+    }
+    let count = numel(&tensor.dims)
+        .ok_or_else(|| ModelError::InvalidFormat(format!("dims {:?} of {} are too large", tensor.dims, tensor.name)))?;
+    tensor.data = raw.chunks_exact(4)
+        .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+        .collect();
+    if tensor.data.len() != count {
+        return Err(ModelError::ShapeMismatch {
+            name: tensor.name,
+            expected: tensor.dims,
+            found: vec![raw.len() / 4],
+        });
+    }
+    Ok(tensor)
+}
+
+struct Array {
+    shape: Vec<usize>,
+    data: Vec<f64>,
+}
+
+impl Array {
+    fn map(&self, f: impl Fn(f64) -> f64) -> Array {
+        Array { shape: self.shape.clone(), data: self.data.iter().map(|&x| f(x)).collect() }
+    }
+}
+
+// Numpy style broadcasting of two arrays.
+fn broadcast(a: &Array, b: &Array, f: impl Fn(f64, f64) -> f64) -> Result<Array, ModelError> {
+    let ndim = a.shape.len().max(b.shape.len());
+    let pad = |s: &[usize]| {
+        let mut padded = vec![1; ndim - s.len()];
+        padded.extend_from_slice(s);
+        padded
+    };
+    let (sa, sb) = (pad(&a.shape), pad(&b.shape));
+    let shape = sa.iter().zip(sb.iter())
+        .map(|(&x, &y)| match (x, y) {
+            _ if x == y || y == 1 => Ok(x),
+            (1, _) => Ok(y),
+            _ => Err(ModelError::ShapeMismatch { name: "broadcast".to_string(), expected: a.shape.clone(), found: b.shape.clone() }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let offset = |s: &[usize], mut flat: usize| {
+        let (mut offset, mut stride) = (0, 1);
+        for d in (0..ndim).rev() {
+            let coord = flat % shape[d];
+            flat /= shape[d];
+            if s[d] != 1 {
+                offset += coord * stride;
+            }
+            stride *= s[d];
+        }
+        offset
+    };
+
+    let data = (0..shape.iter().product())
+        .map(|i| f(a.data[offset(&sa, i)], b.data[offset(&sb, i)]))
+        .collect();
+    Ok(Array { shape, data })
+}
+
+fn gemm(node: &OnnxNode, args: &[&Array]) -> Result<Array, ModelError> {
+    let (a, b) = (args[0], args[1]);
+    let trans_a = node.int_attribute("transA", 0) != 0;
+    let trans_b = node.int_attribute("transB", 0) != 0;
+    let alpha = node.float_attribute("alpha", 1.0);
+    let beta = node.float_attribute("beta", 1.0);
+    if a.shape.len() != 2 || b.shape.len() != 2 {
+        return Err(ModelError::InvalidFormat(format!("Gemm {} needs rank 2 inputs, got {:?} and {:?}", node.name, a.shape, b.shape)));
+    }
+
+    let (n, k) = if trans_a { (a.shape[1], a.shape[0]) } else { (a.shape[0], a.shape[1]) };
+    let (kb, m) = if trans_b { (b.shape[1], b.shape[0]) } else { (b.shape[0], b.shape[1]) };
+    if k != kb {
+        return Err(ModelError::ShapeMismatch { name: node.name.clone(), expected: vec![k], found: vec![kb] });
+    }
+    let at = |i: usize, j: usize| if trans_a { a.data[j * n + i] } else { a.data[i * k + j] };
+    let bt = |i: usize, j: usize| if trans_b { b.data[j * k + i] } else { b.data[i * m + j] };
+
+    let mut data = vec![0.0; n * m];
+    for i in 0..n {
+        for j in 0..m {
+            data[i * m + j] = alpha * (0..k).map(|p| at(i, p) * bt(p, j)).sum::<f64>();
+        }
+    }
+    let out = Array { shape: vec![n, m], data };
+
+    match args.get(2) {
+        Some(c) => broadcast(&out, c, |x, c| x + beta * c),
+        None => Ok(out),
+    }
+}
+
+fn softmax(node: &OnnxNode, x: &Array) -> Result<Array, ModelError> {
+    let rank = x.shape.len() as i64;
+    let axis = node.int_attribute("axis", -1);
+    if axis < -rank || axis >= rank {
+        return Err(ModelError::InvalidFormat(format!("Softmax {} axis {} is out of range for rank {}", node.name, axis, rank)));
+    }
+    let axis = if axis < 0 { rank + axis } else { axis } as usize;
+    let inner = x.shape[axis + 1..].iter().product::<usize>();
+    let len = x.shape[axis];
+    let outer = x.data.len() / (len * inner).max(1);
+
+    let mut data = x.data.clone();
+    for o in 0..outer {
+        for i in 0..inner {
+            let idx = |j: usize| (o * len + j) * inner + i;
+            let max = (0..len).map(|j| x.data[idx(j)]).fold(f64::NEG_INFINITY, f64::max);
+            let sum = (0..len).map(|j| (x.data[idx(j)] - max).exp()).sum::<f64>();
+            for j in 0..len {
+                data[idx(j)] = (x.data[idx(j)] - max).exp() / sum;
+            }
+        }
+    }
+    Ok(Array { shape: x.shape.clone(), data })
+}
+
+fn eval(node: &OnnxNode, args: &[&Array]) -> Result<Array, ModelError> {
+    let arity = match node.op_type.as_str() {
+        "Gemm" => 2,
+        "Add" | "Mul" => 2,
+        _ => 1,
+    };
+    if args.len() < arity {
+        return Err(ModelError::InvalidFormat(format!("{} needs {} inputs", node.op_type, arity)));
+    }
+    // Tensors of a graph built in memory are not checked like decoded ones.
+    if let Some(arg) = args.iter().find(|a| numel(&a.shape) != Some(a.data.len())) {
+        return Err(ModelError::ShapeMismatch { name: node.name.clone(), expected: arg.shape.clone(), found: vec![arg.data.len()] });
+    }
+
+    Ok(match node.op_type.as_str() {
+        "Gemm" => gemm(node, args)?,
+        "Add" => broadcast(args[0], args[1], |a, b| a + b)?,
+        "Mul" => broadcast(args[0], args[1], |a, b| a * b)?,
+        "Identity" => args[0].map(|x| x),
+        "Relu" => args[0].map(|x| Activation::Relu.apply_f64(x)),
+        "LeakyRelu" => args[0].map(|x| Activation::LeakyRelu(node.float_attribute("alpha", 0.01)).apply_f64(x)),
+        "Elu" => args[0].map(|x| Activation::Elu(node.float_attribute("alpha", 1.0)).apply_f64(x)),
+        "Tanh" => args[0].map(|x| Activation::Tanh.apply_f64(x)),
+        "Sigmoid" => args[0].map(|x| Activation::Sigmoid.apply_f64(x)),
+        "Softplus" => args[0].map(|x| Activation::Softplus.apply_f64(x)),
+        "Softmax" => softmax(node, args[0])?,
+        op => return Err(ModelError::InvalidFormat(format!("unsupported op {}", op))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Tensor;
+
+    fn model() -> MLP {
+        MLP::builder(3)
+            .layer(5, Activation::Gelu)
+            .layer(4, Activation::Silu)
+            .layer(4, Activation::LeakyRelu(0.2))
+            .layer(3, Activation::Elu(0.7))
+            .layer(2, Activation::Identity)
+            .build()
+    }
+
+    #[test]
+    fn test_round_trip_matches_forward() {
+        let mlp = model();
+        let input = [0.3, -1.2, 0.8, 1.5, 0.1, -0.4];
+        let expected = mlp.forward_tensor(&Tensor::from_f64(&input, &[2, 3])).data();
+
+        let graph = OnnxGraph::from_bytes(&OnnxExport::new(&mlp).to_bytes()).unwrap();
+        assert_eq!(graph, OnnxExport::new(&mlp).graph().rounded());
+        assert_eq!(graph.opset, OPSET_VERSION);
+
+        let out = graph.run(&input, 2).unwrap();
+        assert_eq!(out.len(), 4);
+        for (a, b) in out.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_softmax() {
+        let mlp = MLP::builder(2)
+            .layer(3, Activation::Relu)
+            .layer(3, Activation::Tanh)
+            .build();
+        let graph = OnnxGraph::from_bytes(&OnnxExport::new(&mlp).softmax(true).to_bytes()).unwrap();
+        let ops = graph.nodes.iter().map(|n| n.op_type.as_str()).collect::<Vec<_>>();
+        assert_eq!(ops, vec!["Gemm", "Relu", "Gemm", "Tanh", "Softmax", "Identity"]);
+
+        let out = graph.run(&[1.0, -2.0, 0.5, 0.5], 2).unwrap();
+        let logits = mlp.forward_tensor(&Tensor::from_f64(&[1.0, -2.0, 0.5, 0.5], &[2, 2])).data();
+        for row in 0..2 {
+            let exps = logits[row * 3..row * 3 + 3].iter().map(|x| x.exp()).collect::<Vec<_>>();
+            let sum = exps.iter().sum::<f64>();
+            assert!((out[row * 3..row * 3 + 3].iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            for j in 0..3 {
+                assert!((out[row * 3 + j] - exps[j] / sum).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(OnnxGraph::from_bytes(&[0x3a, 0x05, 0x01]), Err(ModelError::InvalidFormat(_))));
+        assert!(matches!(OnnxGraph::from_bytes(&[]), Err(ModelError::InvalidFormat(_))));
+        // A length prefix of u64::MAX must not overflow the read position.
+        let huge = [0x0a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(matches!(OnnxGraph::from_bytes(&huge), Err(ModelError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_run_invalid() {
+        let graph = OnnxExport::new(&MLP::new(&[2, 3, 1])).graph();
+        assert!(matches!(graph.run(&[1.0, 2.0], 0), Err(ModelError::InvalidFormat(_))));
+        assert!(matches!(graph.run(&[1.0, 2.0, 3.0], 2), Err(ModelError::InvalidFormat(_))));
+
+        let mut graph = graph;
+        graph.initializers[0].dims = vec![6];
+        assert!(matches!(graph.run(&[1.0, 2.0], 1), Err(ModelError::InvalidFormat(_))));
+        graph.initializers[0].dims = vec![4, 2];
+        assert!(matches!(graph.run(&[1.0, 2.0], 1), Err(ModelError::ShapeMismatch { .. })));
+
+        let mut graph = OnnxExport::new(&MLP::new(&[2, 3])).softmax(true).graph();
+        let softmax = graph.nodes.iter_mut().find(|n| n.op_type == "Softmax").unwrap();
+        softmax.attributes = vec![("axis".to_string(), OnnxAttribute::Int(3))];
+        assert!(matches!(graph.run(&[1.0, 2.0], 1), Err(ModelError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_decode_tensor_dims() {
+        let tensor = |dims: &[i64]| {
+            let mut w = ProtoWriter::default();
+            for &d in dims {
+                w.int(1, d);
+            }
+            w.bytes(9, &[0; 8]);
+            decode_tensor(&w.buf)
+        };
+        assert_eq!(tensor(&[2, 1]).unwrap().dims, vec![2, 1]);
+        assert!(matches!(tensor(&[1 << 40, 1 << 40]), Err(ModelError::InvalidFormat(_))));
+        assert!(matches!(tensor(&[-2, -1]), Err(ModelError::InvalidFormat(_))));
+
+        // The same dims packed into one field.
+        let mut packed = ProtoWriter::default();
+        packed.varint(1 << 40);
+        packed.varint(1 << 40);
+        let mut w = ProtoWriter::default();
+        w.bytes(1, &packed.buf);
+        assert!(matches!(decode_tensor(&w.buf), Err(ModelError::InvalidFormat(_))));
+    }
+
+    impl OnnxGraph {
+        // The graph as it reads back after storing the data as f32.
+        fn rounded(mut self) -> OnnxGraph {
+            for t in self.initializers.iter_mut() {
+                t.data.iter_mut().for_each(|x| *x = *x as f32 as f64);
+            }
+            self
+        }
+    }
+}
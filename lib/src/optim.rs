@@ -0,0 +1,499 @@
+use crate::Value;
+
+/// Updates a set of parameters from their gradients.
+pub trait Optimizer {
+    fn step(&mut self);
+
+    fn zero_grad(&self) {
+        for p in self.parameters() {
+            p.zero_grad();
+        }
+    }
+
+    fn parameters(&self) -> &[Value];
+
+    fn learning_rate(&self) -> f64;
+
+    fn set_learning_rate(&mut self, learning_rate: f64);
+
+    /// Number of `step` calls so far.
+    fn steps(&self) -> usize;
+
+    /// Per-parameter state buffers by name, e.g. `("exp_avg", ..)` for Adam.
+    fn state(&self) -> Vec<(&'static str, &[f64])>;
+}
+
+fn owned(params: Vec<&Value>) -> Vec<Value> {
+    params.into_iter().cloned().collect()
+}
+
+/// Stochastic gradient descent with optional momentum, Nesterov momentum and
+/// L2 weight decay.
+#[derive(Debug)]
+pub struct Sgd {
+    params: Vec<Value>,
+    learning_rate: f64,
+    momentum: f64,
+    nesterov: bool,
+    weight_decay: f64,
+    velocity: Vec<f64>,
+    steps: usize,
+}
+
+impl Sgd {
+    pub fn new(params: Vec<&Value>, learning_rate: f64) -> Sgd {
+        let params = owned(params);
+        Sgd {
+            velocity: vec![0.0; params.len()],
+            params,
+            learning_rate,
+            momentum: 0.0,
+            nesterov: false,
+            weight_decay: 0.0,
+            steps: 0,
+        }
+    }
+
+    pub fn momentum(mut self, momentum: f64) -> Sgd {
+        self.momentum = momentum;
+        self
+    }
+
+    pub fn nesterov(mut self, nesterov: bool) -> Sgd {
+        self.nesterov = nesterov;
+        self
+    }
+
+    pub fn weight_decay(mut self, weight_decay: f64) -> Sgd {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimizer for Sgd {
+    fn step(&mut self) {
+        for (p, v) in self.params.iter().zip(self.velocity.iter_mut()) {
+            let mut g = p.grad() + self.weight_decay * p.data();
+
+            if self.momentum != 0.0 {
+                *v = self.momentum * *v + g;
+                g = if self.nesterov { g + self.momentum * *v } else { *v };
+            }
+
+            p.decrease_data(self.learning_rate * g);
+        }
+        self.steps += 1;
+    }
+
+    fn parameters(&self) -> &[Value] {
+        &self.params
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn state(&self) -> Vec<(&'static str, &[f64])> {
+        vec![("momentum_buffer", &self.velocity)]
+    }
+}
+
+/// Adam with L2 weight decay added to the gradient.
+#[derive(Debug)]
+pub struct Adam {
+    params: Vec<Value>,
+    learning_rate: f64,
+    beta1: f64,
+    beta2: f64,
+    eps: f64,
+    weight_decay: f64,
+    decoupled_weight_decay: bool,
+    exp_avg: Vec<f64>,
+    exp_avg_sq: Vec<f64>,
+    steps: usize,
+}
+
+impl Adam {
+    pub fn new(params: Vec<&Value>, learning_rate: f64) -> Adam {
+        let params = owned(params);
+        Adam {
+            exp_avg: vec![0.0; params.len()],
+            exp_avg_sq: vec![0.0; params.len()],
+            params,
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.0,
+            decoupled_weight_decay: false,
+            steps: 0,
+        }
+    }
+
+    pub fn betas(mut self, beta1: f64, beta2: f64) -> Adam {
+        self.beta1 = beta1;
+        self.beta2 = beta2;
+        self
+    }
+
+    pub fn eps(mut self, eps: f64) -> Adam {
+        self.eps = eps;
+        self
+    }
+
+    pub fn weight_decay(mut self, weight_decay: f64) -> Adam {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self) {
+        self.steps += 1;
+        let bias_correction1 = 1.0 - self.beta1.powi(self.steps as i32);
+        let bias_correction2 = 1.0 - self.beta2.powi(self.steps as i32);
+
+        for (i, p) in self.params.iter().enumerate() {
+            let mut g = p.grad();
+            if self.decoupled_weight_decay {
+                p.decrease_data(self.learning_rate * self.weight_decay * p.data());
+            } else {
+                g += self.weight_decay * p.data();
+            }
+
+            let m = &mut self.exp_avg[i];
+            let v = &mut self.exp_avg_sq[i];
+            *m = self.beta1 * *m + (1.0 - self.beta1) * g;
+            *v = self.beta2 * *v + (1.0 - self.beta2) * g * g;
+
+            let m_hat = *m / bias_correction1;
+            let v_hat = *v / bias_correction2;
+            p.decrease_data(self.learning_rate * m_hat / (v_hat.sqrt() + self.eps));
+        }
+    }
+
+    fn parameters(&self) -> &[Value] {
+        &self.params
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn state(&self) -> Vec<(&'static str, &[f64])> {
+        vec![("exp_avg", &self.exp_avg), ("exp_avg_sq", &self.exp_avg_sq)]
+    }
+}
+
+/// Adam with weight decay applied directly to the parameters instead of
+/// through the gradient.
+#[derive(Debug)]
+pub struct AdamW {
+    adam: Adam,
+}
+
+impl AdamW {
+    pub fn new(params: Vec<&Value>, learning_rate: f64) -> AdamW {
+        let mut adam = Adam::new(params, learning_rate).weight_decay(0.01);
+        adam.decoupled_weight_decay = true;
+        AdamW { adam }
+    }
+
+    pub fn betas(self, beta1: f64, beta2: f64) -> AdamW {
+        AdamW { adam: self.adam.betas(beta1, beta2) }
+    }
+
+    pub fn eps(self, eps: f64) -> AdamW {
+        AdamW { adam: self.adam.eps(eps) }
+    }
+
+    pub fn weight_decay(self, weight_decay: f64) -> AdamW {
+        AdamW { adam: self.adam.weight_decay(weight_decay) }
+    }
+}
+
+impl Optimizer for AdamW {
+    fn step(&mut self) {
+        self.adam.step()
+    }
+
+    fn parameters(&self) -> &[Value] {
+        self.adam.parameters()
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.adam.learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.adam.set_learning_rate(learning_rate)
+    }
+
+    fn steps(&self) -> usize {
+        self.adam.steps()
+    }
+
+    fn state(&self) -> Vec<(&'static str, &[f64])> {
+        self.adam.state()
+    }
+}
+
+#[derive(Debug)]
+pub struct RmsProp {
+    params: Vec<Value>,
+    learning_rate: f64,
+    alpha: f64,
+    eps: f64,
+    momentum: f64,
+    weight_decay: f64,
+    square_avg: Vec<f64>,
+    momentum_buffer: Vec<f64>,
+    steps: usize,
+}
+
+impl RmsProp {
+    pub fn new(params: Vec<&Value>, learning_rate: f64) -> RmsProp {
+        let params = owned(params);
+        RmsProp {
+            square_avg: vec![0.0; params.len()],
+            momentum_buffer: vec![0.0; params.len()],
+            params,
+            learning_rate,
+            alpha: 0.99,
+            eps: 1e-8,
+            momentum: 0.0,
+            weight_decay: 0.0,
+            steps: 0,
+        }
+    }
+
+    pub fn alpha(mut self, alpha: f64) -> RmsProp {
+        self.alpha = alpha;
+        self
+    }
+
+    pub fn eps(mut self, eps: f64) -> RmsProp {
+        self.eps = eps;
+        self
+    }
+
+    pub fn momentum(mut self, momentum: f64) -> RmsProp {
+        self.momentum = momentum;
+        self
+    }
+
+    pub fn weight_decay(mut self, weight_decay: f64) -> RmsProp {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimizer for RmsProp {
+    fn step(&mut self) {
+        for (i, p) in self.params.iter().enumerate() {
+            let g = p.grad() + self.weight_decay * p.data();
+
+            let v = &mut self.square_avg[i];
+            *v = self.alpha * *v + (1.0 - self.alpha) * g * g;
+            let mut update = g / (v.sqrt() + self.eps);
+
+            if self.momentum != 0.0 {
+                let buf = &mut self.momentum_buffer[i];
+                *buf = self.momentum * *buf + update;
+                update = *buf;
+            }
+
+            p.decrease_data(self.learning_rate * update);
+        }
+        self.steps += 1;
+    }
+
+    fn parameters(&self) -> &[Value] {
+        &self.params
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn state(&self) -> Vec<(&'static str, &[f64])> {
+        vec![("square_avg", &self.square_avg), ("momentum_buffer", &self.momentum_buffer)]
+    }
+}
+
+#[derive(Debug)]
+pub struct Adagrad {
+    params: Vec<Value>,
+    learning_rate: f64,
+    eps: f64,
+    weight_decay: f64,
+    sum: Vec<f64>,
+    steps: usize,
+}
+
+impl Adagrad {
+    pub fn new(params: Vec<&Value>, learning_rate: f64) -> Adagrad {
+        let params = owned(params);
+        Adagrad {
+            sum: vec![0.0; params.len()],
+            params,
+            learning_rate,
+            eps: 1e-10,
+            weight_decay: 0.0,
+            steps: 0,
+        }
+    }
+
+    pub fn eps(mut self, eps: f64) -> Adagrad {
+        self.eps = eps;
+        self
+    }
+
+    pub fn weight_decay(mut self, weight_decay: f64) -> Adagrad {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimizer for Adagrad {
+    fn step(&mut self) {
+        for (p, sum) in self.params.iter().zip(self.sum.iter_mut()) {
+            let g = p.grad() + self.weight_decay * p.data();
+            *sum += g * g;
+            p.decrease_data(self.learning_rate * g / (sum.sqrt() + self.eps));
+        }
+        self.steps += 1;
+    }
+
+    fn parameters(&self) -> &[Value] {
+        &self.params
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn state(&self) -> Vec<(&'static str, &[f64])> {
+        vec![("sum", &self.sum)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimizes (x - 3)^2 + (y + 1)^2 and returns the final point.
+    fn minimize(optimizer: &mut dyn Optimizer, steps: usize) -> (f64, f64) {
+        for _ in 0..steps {
+            optimizer.zero_grad();
+            let params = optimizer.parameters();
+            let loss = (&params[0] - 3.0).square() + (&params[1] + 1.0).square();
+            loss.backward();
+            optimizer.step();
+        }
+        let params = optimizer.parameters();
+        (params[0].data(), params[1].data())
+    }
+
+    fn assert_converges(optimizer: &mut dyn Optimizer, steps: usize) {
+        let (x, y) = minimize(optimizer, steps);
+        assert!((x - 3.0).abs() < 1e-2 && (y + 1.0).abs() < 1e-2, "ended at ({}, {})", x, y);
+    }
+
+    #[test]
+    fn test_sgd_step() {
+        let x = Value::new(1.0);
+        let mut sgd = Sgd::new(vec![&x], 0.1).momentum(0.9);
+
+        x.square().backward();
+        sgd.step();
+        assert!((x.data() - 0.8).abs() < 1e-12);
+
+        sgd.zero_grad();
+        assert_eq!(x.grad(), 0.0);
+        x.square().backward();
+        sgd.step();
+        // v = 0.9 * 2 + 1.6 = 3.4
+        assert!((x.data() - (0.8 - 0.34)).abs() < 1e-12);
+        assert_eq!(sgd.steps(), 2);
+        assert_eq!(sgd.state()[0].0, "momentum_buffer");
+        assert!((sgd.state()[0].1[0] - 3.4).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_adam_first_step_is_learning_rate() {
+        let x = Value::new(1.0);
+        let mut adam = Adam::new(vec![&x], 0.01);
+        x.square().backward();
+        adam.step();
+        assert!((x.data() - 0.99).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_adamw_decays_without_grad() {
+        let x = Value::new(1.0);
+        let mut adamw = AdamW::new(vec![&x], 0.1).weight_decay(0.5);
+        adamw.step();
+        assert!((x.data() - 0.95).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_optimizers_converge() {
+        let params = || [Value::new(0.0), Value::new(0.0)];
+
+        let p = params();
+        assert_converges(&mut Sgd::new(p.iter().collect(), 0.1), 200);
+        let p = params();
+        assert_converges(&mut Sgd::new(p.iter().collect(), 0.05).momentum(0.9), 300);
+        let p = params();
+        assert_converges(&mut Sgd::new(p.iter().collect(), 0.05).momentum(0.9).nesterov(true), 300);
+        let p = params();
+        assert_converges(&mut Adam::new(p.iter().collect(), 0.1), 500);
+        let p = params();
+        assert_converges(&mut AdamW::new(p.iter().collect(), 0.1).weight_decay(0.0), 500);
+        let p = params();
+        assert_converges(&mut RmsProp::new(p.iter().collect(), 0.01), 1000);
+        let p = params();
+        assert_converges(&mut Adagrad::new(p.iter().collect(), 0.5), 1000);
+    }
+
+    #[test]
+    fn test_set_learning_rate() {
+        let x = Value::new(1.0);
+        let mut sgd = Sgd::new(vec![&x], 0.1);
+        sgd.set_learning_rate(0.5);
+        assert_eq!(sgd.learning_rate(), 0.5);
+    }
+}
@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// Seeds the rng used by this thread for initialization, shuffling and
+/// anything else that draws random numbers without an explicit rng, so a
+/// run can be reproduced exactly.
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+/// Runs `f` with this thread's crate rng. Seeded from entropy unless `seed`
+/// has been called. Not re-entrant: `f` must not call `with_rng` or anything
+/// that uses it, like `MLP::new`, and should pass the rng on instead (e.g. to
+/// `MLP::with_rng`). Doing so panics.
+pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    RNG.with(|rng| {
+        let mut rng = rng.try_borrow_mut()
+            .expect("with_rng is not re-entrant, pass the rng on instead of calling with_rng or MLP::new inside it");
+        f(&mut rng)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::seq::SliceRandom;
+
+    use super::*;
+    use crate::MLP;
+    use crate::Module;
+    use crate::Optimizer;
+    use crate::Sgd;
+    use crate::Tape;
+
+    // A tiny MNIST-style run: random 16 pixel "images" with 4 classes,
+    // shuffled every epoch and trained with minibatches on the tape.
+    fn run(seed_value: u64) -> Vec<f64> {
+        seed(seed_value);
+
+        let mut samples = with_rng(|rng| {
+            (0..32)
+                .map(|_| {
+                    let pixels = (0..16).map(|_| rng.gen::<f64>()).collect::<Vec<_>>();
+                    (pixels, rng.gen_range(0..4))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mlp = MLP::new(&[16, 8, 4]);
+        let mut optimizer = Sgd::new(mlp.parameters(), 0.1).momentum(0.9);
+        let mut tape = Tape::new();
+        let mut losses = vec![];
+
+        for _ in 0..3 {
+            with_rng(|rng| samples.shuffle(rng));
+
+            for batch in samples.chunks(8) {
+                let pixels = batch.iter().flat_map(|(p, _)| p.iter().cloned()).collect();
+                let labels = batch.iter().map(|(_, l)| *l).collect::<Vec<_>>();
+
+                tape.clear();
+                let input = tape.leaf(pixels, labels.len(), 16);
+                let out = mlp.forward_tape(&mut tape, input);
+                let loss = tape.cross_entropy(out, &labels);
+                tape.backward(loss);
+                losses.push(tape.value(loss)[0]);
+
+                optimizer.step();
+                optimizer.zero_grad();
+            }
+        }
+
+        losses
+    }
+
+    #[test]
+    #[should_panic(expected = "with_rng is not re-entrant")]
+    fn test_with_rng_is_not_reentrant() {
+        with_rng(|_| MLP::new(&[2, 1]));
+    }
+
+    #[test]
+    fn test_seeded_runs_are_identical() {
+        let first = run(1234);
+        let second = run(1234);
+        assert_eq!(first.len(), 12);
+        assert_eq!(
+            first.iter().map(|l| l.to_bits()).collect::<Vec<_>>(),
+            second.iter().map(|l| l.to_bits()).collect::<Vec<_>>()
+        );
+
+        assert_ne!(first, run(4321));
+    }
+}
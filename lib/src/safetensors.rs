@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Module;
+use crate::ModelError;
+use crate::Value;
+
+/// Element types read and written by the safetensors functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Dtype {
+    F32,
+    F64,
+}
+
+impl Dtype {
+    fn size(&self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F64 => 8,
+        }
+    }
+}
+
+/// One named tensor of a safetensors file, stored row-major as `f64`
+/// whatever its dtype on disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafeTensor {
+    pub name: String,
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HeaderEntry {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: [usize; 2],
+}
+
+// A parameter and its index within its tensor.
+type Element = (Vec<usize>, Value);
+// A tensor's name, shape and parameters in row-major order.
+type ParameterTensor = (String, Vec<usize>, Vec<Value>);
+
+/// Groups the named parameters of `model` into tensors by their trailing
+/// numeric indices, so `layers.0.weight.3.1` is element `[3, 1]` of
+/// `layers.0.weight` and `layers.0.bias.3` element `[3]` of `layers.0.bias`,
+/// matching the names of a PyTorch `Linear` in a `ModuleList` called `layers`.
+/// Fails if the indices of a name do not fill a dense tensor.
+fn parameter_tensors<M: Module + ?Sized>(model: &M) -> Result<Vec<ParameterTensor>, ModelError> {
+    let mut groups: Vec<(String, Vec<Element>)> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (name, value) in model.named_parameters() {
+        let parts = name.split('.').collect::<Vec<_>>();
+        let split = parts.iter().rposition(|p| p.parse::<usize>().is_err()).map_or(0, |i| i + 1);
+        let base = parts[..split].join(".");
+        let index = parts[split..].iter().map(|p| p.parse().unwrap()).collect::<Vec<usize>>();
+
+        let position = *positions.entry(base.clone()).or_insert_with(|| {
+            groups.push((base, vec![]));
+            groups.len() - 1
+        });
+        groups[position].1.push((index, value.clone()));
+    }
+
+    groups.into_iter()
+        .map(|(name, elements)| {
+            let not_dense = || ModelError::InvalidFormat(format!("parameters of {} do not form a dense tensor", name));
+
+            let ndim = elements[0].0.len();
+            if elements.iter().any(|(index, _)| index.len() != ndim) {
+                return Err(not_dense());
+            }
+            let shape = (0..ndim)
+                .map(|d| elements.iter().map(|(index, _)| index[d] + 1).max().unwrap())
+                .collect::<Vec<_>>();
+            // Each name is one element, so a dense tensor has exactly as many.
+            if shape.iter().try_fold(1usize, |acc, &n| acc.checked_mul(n)) != Some(elements.len()) {
+                return Err(not_dense());
+            }
+
+            let mut values = vec![None; elements.len()];
+            for (index, value) in elements {
+                let flat = index.iter().zip(shape.iter()).fold(0, |acc, (i, n)| acc * n + i);
+                values[flat] = Some(value);
+            }
+            let values = values.into_iter()
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(not_dense)?;
+
+            Ok((name, shape, values))
+        })
+        .collect()
+}
+
+/// The parameters of `model` as tensors, e.g. `[out, in]` weights and `[out]`
+/// biases for each layer of an `MLP`.
+pub fn module_tensors<M: Module + ?Sized>(model: &M, dtype: Dtype) -> Result<Vec<SafeTensor>, ModelError> {
+    Ok(parameter_tensors(model)?
+        .into_iter()
+        .map(|(name, shape, values)| SafeTensor {
+            name,
+            dtype,
+            shape,
+            data: values.iter().map(|v| v.data()).collect(),
+        })
+        .collect())
+}
+
+/// Encodes `tensors` as a safetensors file: an 8 byte little endian header
+/// length, a JSON header and the raw little endian data.
+pub fn encode_safetensors(tensors: &[SafeTensor]) -> Vec<u8> {
+    let mut header = serde_json::Map::new();
+    let mut data = Vec::new();
+
+    for tensor in tensors {
+        let begin = data.len();
+        for &x in &tensor.data {
+            match tensor.dtype {
+                Dtype::F32 => data.extend_from_slice(&(x as f32).to_le_bytes()),
+                Dtype::F64 => data.extend_from_slice(&x.to_le_bytes()),
+            }
+        }
+        let entry = HeaderEntry {
+            dtype: format!("{:?}", tensor.dtype),
+            shape: tensor.shape.clone(),
+            data_offsets: [begin, data.len()],
+        };
+        header.insert(tensor.name.clone(), serde_json::to_value(entry).unwrap());
+    }
+
+    let mut header = serde_json::to_vec(&header).unwrap();
+    // Pad with spaces so the data starts 8 byte aligned.
+    while header.len() % 8 != 0 {
+        header.push(b' ');
+    }
+
+    let mut out = Vec::with_capacity(8 + header.len() + data.len());
+    out.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&data);
+    out
+}
+
+pub fn decode_safetensors(bytes: &[u8]) -> Result<Vec<SafeTensor>, ModelError> {
+    let invalid = |msg: String| ModelError::InvalidFormat(msg);
+
+    if bytes.len() < 8 {
+        return Err(invalid("missing safetensors header length".to_string()));
+    }
+    let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+    if header_len > bytes.len() - 8 {
+        return Err(invalid(format!("header length {} exceeds file size", header_len)));
+    }
+    let header: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&bytes[8..8 + header_len])?;
+    let data = &bytes[8 + header_len..];
+
+    let mut tensors = vec![];
+    for (name, entry) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let entry: HeaderEntry = serde_json::from_value(entry)?;
+        let dtype = match entry.dtype.as_str() {
+            "F32" => Dtype::F32,
+            "F64" => Dtype::F64,
+            other => return Err(invalid(format!("unsupported dtype {} for {}", other, name))),
+        };
+
+        let [begin, end] = entry.data_offsets;
+        let size = entry.shape.iter()
+            .try_fold(dtype.size(), |acc, &d| acc.checked_mul(d))
+            .ok_or_else(|| invalid(format!("shape {:?} of {} is too large", entry.shape, name)))?;
+        if begin > end || end > data.len() || end - begin != size {
+            return Err(invalid(format!("bad data offsets {:?} for {}", entry.data_offsets, name)));
+        }
+
+        let raw = &data[begin..end];
+        let values = match dtype {
+            Dtype::F32 => raw.chunks_exact(4)
+                .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+                .collect(),
+            Dtype::F64 => raw.chunks_exact(8)
+                .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+                .collect(),
+        };
+
+        tensors.push((begin, SafeTensor {
+            name,
+            dtype,
+            shape: entry.shape,
+            data: values,
+        }));
+    }
+
+    // The JSON map is unordered, keep the file's data order instead.
+    tensors.sort_by_key(|(begin, _)| *begin);
+    Ok(tensors.into_iter().map(|(_, t)| t).collect())
+}
+
+/// Copies the tensors into the parameters of `model`. Every parameter tensor
+/// must be present with the same shape, extra tensors are ignored.
+pub fn load_tensors<M: Module + ?Sized>(model: &M, tensors: &[SafeTensor]) -> Result<(), ModelError> {
+    for (name, shape, values) in parameter_tensors(model)? {
+        let tensor = tensors.iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| ModelError::MissingTensor(name.clone()))?;
+        if tensor.shape != shape {
+            return Err(ModelError::ShapeMismatch {
+                name,
+                expected: shape,
+                found: tensor.shape.clone(),
+            });
+        }
+
+        for (value, &x) in values.iter().zip(tensor.data.iter()) {
+            value.set_data(x);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn save_safetensors<M: Module + ?Sized>(model: &M, path: impl AsRef<Path>, dtype: Dtype) -> Result<(), ModelError> {
+    fs::write(path, encode_safetensors(&module_tensors(model, dtype)?))?;
+    Ok(())
+}
+
+pub fn load_safetensors<M: Module + ?Sized>(model: &M, path: impl AsRef<Path>) -> Result<(), ModelError> {
+    load_tensors(model, &decode_safetensors(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Activation;
+    use crate::Neuron;
+    use crate::MLP;
+
+    fn data<M: Module>(model: &M) -> Vec<f64> {
+        model.parameters().iter().map(|p| p.data()).collect()
+    }
+
+    #[test]
+    fn test_module_tensors() {
+        let mlp = MLP::new(&[3, 4, 2]);
+        let tensors = module_tensors(&mlp, Dtype::F64).unwrap();
+        let names = tensors.iter().map(|t| (t.name.as_str(), t.shape.clone())).collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            ("layers.0.bias", vec![4]),
+            ("layers.0.weight", vec![4, 3]),
+            ("layers.1.bias", vec![2]),
+            ("layers.1.weight", vec![2, 4]),
+        ]);
+        assert_eq!(tensors[1].data[3 + 2], mlp.layers()[0].neurons()[1].weights()[2].data());
+
+        let neuron = Neuron::from_weights(&[1.0, 2.0], 3.0, Activation::Identity);
+        let tensors = module_tensors(&neuron, Dtype::F64).unwrap();
+        assert_eq!((tensors[0].name.as_str(), tensors[0].shape.clone()), ("bias", vec![]));
+        assert_eq!(tensors[1].data, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mlp = MLP::new(&[3, 4, 2]);
+        let other = MLP::new(&[3, 4, 2]);
+
+        let bytes = encode_safetensors(&module_tensors(&mlp, Dtype::F64).unwrap());
+        let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        assert_eq!(header_len % 8, 0);
+        load_tensors(&other, &decode_safetensors(&bytes).unwrap()).unwrap();
+        assert_eq!(data(&other), data(&mlp));
+
+        let bytes = encode_safetensors(&module_tensors(&mlp, Dtype::F32).unwrap());
+        load_tensors(&other, &decode_safetensors(&bytes).unwrap()).unwrap();
+        for (a, b) in data(&other).iter().zip(data(&mlp)) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    // The weights of `layers = ModuleList([Linear(2, 2), Linear(2, 1)])` as
+    // `safetensors.torch.save_file` writes them, produced with `serialize` of
+    // the `safetensors` crate 0.4.5 that the Python package wraps, with the
+    // `{"format": "pt"}` metadata torch adds.
+    #[test]
+    fn test_load_pytorch_file() {
+        let bytes = include_bytes!("../testdata/pytorch_mlp.safetensors");
+        let decoded = decode_safetensors(bytes).unwrap();
+        // The file orders the tensors by name and skips `__metadata__`.
+        assert_eq!(decoded, vec![
+            SafeTensor { name: "layers.0.bias".into(), dtype: Dtype::F32, shape: vec![2], data: vec![0.0, -0.5] },
+            SafeTensor { name: "layers.0.weight".into(), dtype: Dtype::F32, shape: vec![2, 2], data: vec![1.0, -1.0, 0.5, 2.0] },
+            SafeTensor { name: "layers.1.bias".into(), dtype: Dtype::F32, shape: vec![1], data: vec![0.25] },
+            SafeTensor { name: "layers.1.weight".into(), dtype: Dtype::F32, shape: vec![1, 2], data: vec![3.0, 1.0] },
+        ]);
+
+        let mlp = MLP::new(&[2, 2, 1]);
+        load_tensors(&mlp, &decoded).unwrap();
+
+        // relu([1 - 2, 0.5 + 4 - 0.5]) = [0, 4], then 3 * 0 + 4 + 0.25.
+        let out = mlp.forward(vec![Value::new(1.0), Value::new(2.0)]);
+        assert_eq!(out[0].data(), 4.25);
+    }
+
+    // Parameters named like a tensor with a missing element.
+    struct Sparse(Vec<Value>);
+
+    impl Module for Sparse {
+        fn forward(&self, input: Vec<Value>) -> Vec<Value> {
+            input
+        }
+
+        fn parameters(&self) -> Vec<&Value> {
+            self.0.iter().collect()
+        }
+
+        fn named_parameters(&self) -> Vec<(String, &Value)> {
+            vec![("w.0".to_string(), &self.0[0]), ("w.2".to_string(), &self.0[1])]
+        }
+
+        fn set_training(&mut self, _training: bool) {}
+
+        fn is_training(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_non_dense_parameters() {
+        let sparse = Sparse(vec![Value::new(1.0), Value::new(2.0)]);
+        assert!(matches!(module_tensors(&sparse, Dtype::F64), Err(ModelError::InvalidFormat(_))));
+        assert!(matches!(load_tensors(&sparse, &[]), Err(ModelError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mlp = MLP::new(&[2, 2, 1]);
+        let mut tensors = module_tensors(&mlp, Dtype::F64).unwrap();
+        tensors[1].shape = vec![4];
+        let err = load_tensors(&mlp, &tensors).unwrap_err();
+        assert!(matches!(err, ModelError::ShapeMismatch { ref name, .. } if name == "layers.0.weight"));
+
+        tensors.remove(1);
+        assert!(matches!(load_tensors(&mlp, &tensors), Err(ModelError::MissingTensor(_))));
+
+        let mut bytes = encode_safetensors(&module_tensors(&mlp, Dtype::F64).unwrap());
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(decode_safetensors(&bytes), Err(ModelError::InvalidFormat(_))));
+
+        // A shape whose byte size overflows usize.
+        let header = br#"{"w":{"dtype":"F64","shape":[1099511627776,1099511627776],"data_offsets":[0,8]}}"#;
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header);
+        bytes.extend_from_slice(&[0; 8]);
+        assert!(matches!(decode_safetensors(&bytes), Err(ModelError::InvalidFormat(_))));
+    }
+}
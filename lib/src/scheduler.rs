@@ -0,0 +1,445 @@
+use std::f64::consts::PI;
+
+use crate::Optimizer;
+
+/// A learning rate schedule. `step` is meant to be called once per epoch or
+/// once per batch, depending on how the schedule was configured.
+pub trait LrScheduler {
+    /// Learning rate for the current step.
+    fn lr(&self) -> f64;
+
+    /// Moves to the next step without touching any optimizer.
+    fn advance(&mut self);
+
+    fn apply(&self, optimizer: &mut dyn Optimizer) {
+        optimizer.set_learning_rate(self.lr());
+    }
+
+    fn step(&mut self, optimizer: &mut dyn Optimizer) {
+        self.advance();
+        self.apply(optimizer);
+    }
+}
+
+/// Multiplies the learning rate by `gamma` every `step_size` steps.
+#[derive(Debug, Clone)]
+pub struct StepLr {
+    base_lr: f64,
+    step_size: usize,
+    gamma: f64,
+    steps: usize,
+}
+
+impl StepLr {
+    pub fn new(base_lr: f64, step_size: usize, gamma: f64) -> StepLr {
+        assert!(step_size > 0, "step_size must be positive");
+        StepLr { base_lr, step_size, gamma, steps: 0 }
+    }
+}
+
+impl LrScheduler for StepLr {
+    fn lr(&self) -> f64 {
+        self.base_lr * self.gamma.powi((self.steps / self.step_size) as i32)
+    }
+
+    fn advance(&mut self) {
+        self.steps += 1;
+    }
+}
+
+/// Multiplies the learning rate by `gamma` every step.
+#[derive(Debug, Clone)]
+pub struct ExponentialLr {
+    base_lr: f64,
+    gamma: f64,
+    steps: usize,
+}
+
+impl ExponentialLr {
+    pub fn new(base_lr: f64, gamma: f64) -> ExponentialLr {
+        ExponentialLr { base_lr, gamma, steps: 0 }
+    }
+}
+
+impl LrScheduler for ExponentialLr {
+    fn lr(&self) -> f64 {
+        self.base_lr * self.gamma.powi(self.steps as i32)
+    }
+
+    fn advance(&mut self) {
+        self.steps += 1;
+    }
+}
+
+/// Cosine annealing from `base_lr` down to `eta_min` over `t0` steps, then
+/// restarting with a period multiplied by `t_mult` each time (SGDR).
+#[derive(Debug, Clone)]
+pub struct CosineAnnealingWarmRestarts {
+    base_lr: f64,
+    t0: usize,
+    t_mult: usize,
+    eta_min: f64,
+    steps: usize,
+}
+
+impl CosineAnnealingWarmRestarts {
+    pub fn new(base_lr: f64, t0: usize, t_mult: usize, eta_min: f64) -> CosineAnnealingWarmRestarts {
+        assert!(t0 > 0, "t0 must be positive");
+        assert!(t_mult > 0, "t_mult must be positive");
+        CosineAnnealingWarmRestarts { base_lr, t0, t_mult, eta_min, steps: 0 }
+    }
+}
+
+impl LrScheduler for CosineAnnealingWarmRestarts {
+    fn lr(&self) -> f64 {
+        let mut t_cur = self.steps;
+        let mut t_i = self.t0;
+        while t_cur >= t_i {
+            t_cur -= t_i;
+            t_i *= self.t_mult;
+        }
+
+        let progress = t_cur as f64 / t_i as f64;
+        self.eta_min + (self.base_lr - self.eta_min) * (1.0 + (PI * progress).cos()) / 2.0
+    }
+
+    fn advance(&mut self) {
+        self.steps += 1;
+    }
+}
+
+/// Ramps the learning rate linearly from `base_lr * start_factor` to
+/// `base_lr` over `warmup_steps`, then hands over to an optional schedule.
+pub struct LinearWarmup {
+    base_lr: f64,
+    warmup_steps: usize,
+    start_factor: f64,
+    after: Option<Box<dyn LrScheduler>>,
+    steps: usize,
+}
+
+impl LinearWarmup {
+    pub fn new(base_lr: f64, warmup_steps: usize) -> LinearWarmup {
+        LinearWarmup {
+            base_lr,
+            warmup_steps,
+            start_factor: 0.0,
+            after: None,
+            steps: 0,
+        }
+    }
+
+    pub fn start_factor(mut self, start_factor: f64) -> LinearWarmup {
+        self.start_factor = start_factor;
+        self
+    }
+
+    /// Schedule to follow once the warmup is done. It starts from its own
+    /// first step.
+    pub fn then(mut self, scheduler: impl LrScheduler + 'static) -> LinearWarmup {
+        self.after = Some(Box::new(scheduler));
+        self
+    }
+}
+
+impl LrScheduler for LinearWarmup {
+    fn lr(&self) -> f64 {
+        if self.steps < self.warmup_steps {
+            let progress = self.steps as f64 / self.warmup_steps as f64;
+            return self.base_lr * (self.start_factor + (1.0 - self.start_factor) * progress);
+        }
+
+        match &self.after {
+            Some(after) => after.lr(),
+            None => self.base_lr,
+        }
+    }
+
+    fn advance(&mut self) {
+        if self.steps >= self.warmup_steps {
+            if let Some(after) = self.after.as_mut() {
+                after.advance();
+            }
+        }
+        self.steps += 1;
+    }
+}
+
+/// The one-cycle policy: cosine warmup from `max_lr / div_factor` to `max_lr`
+/// for the first `pct_start` of `total_steps`, then cosine annealing down to
+/// `max_lr / (div_factor * final_div_factor)`.
+#[derive(Debug, Clone)]
+pub struct OneCycleLr {
+    max_lr: f64,
+    total_steps: usize,
+    pct_start: f64,
+    div_factor: f64,
+    final_div_factor: f64,
+    steps: usize,
+}
+
+impl OneCycleLr {
+    pub fn new(max_lr: f64, total_steps: usize) -> OneCycleLr {
+        assert!(total_steps > 1, "total_steps must be at least 2");
+        OneCycleLr {
+            max_lr,
+            total_steps,
+            pct_start: 0.3,
+            div_factor: 25.0,
+            final_div_factor: 1e4,
+            steps: 0,
+        }
+    }
+
+    pub fn pct_start(mut self, pct_start: f64) -> OneCycleLr {
+        self.pct_start = pct_start;
+        self
+    }
+
+    pub fn div_factor(mut self, div_factor: f64) -> OneCycleLr {
+        self.div_factor = div_factor;
+        self
+    }
+
+    pub fn final_div_factor(mut self, final_div_factor: f64) -> OneCycleLr {
+        self.final_div_factor = final_div_factor;
+        self
+    }
+}
+
+fn cosine_between(start: f64, end: f64, progress: f64) -> f64 {
+    end + (start - end) * (1.0 + (PI * progress).cos()) / 2.0
+}
+
+impl LrScheduler for OneCycleLr {
+    fn lr(&self) -> f64 {
+        let initial_lr = self.max_lr / self.div_factor;
+        let min_lr = initial_lr / self.final_div_factor;
+        let last = (self.total_steps - 1) as f64;
+        let peak = (self.pct_start * last).max(1.0);
+        let step = (self.steps as f64).min(last);
+
+        if step <= peak {
+            cosine_between(initial_lr, self.max_lr, step / peak)
+        } else {
+            cosine_between(self.max_lr, min_lr, (step - peak) / (last - peak))
+        }
+    }
+
+    fn advance(&mut self) {
+        self.steps += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlateauMode {
+    /// The metric should go down, e.g. a loss.
+    Min,
+    /// The metric should go up, e.g. an accuracy.
+    Max,
+}
+
+/// Multiplies the learning rate by `factor` when the reported metric has not
+/// improved for `patience` steps.
+#[derive(Debug, Clone)]
+pub struct ReduceLrOnPlateau {
+    lr: f64,
+    mode: PlateauMode,
+    factor: f64,
+    patience: usize,
+    threshold: f64,
+    cooldown: usize,
+    min_lr: f64,
+    best: f64,
+    bad_steps: usize,
+    cooldown_left: usize,
+}
+
+impl ReduceLrOnPlateau {
+    pub fn new(lr: f64, mode: PlateauMode) -> ReduceLrOnPlateau {
+        ReduceLrOnPlateau {
+            lr,
+            mode,
+            factor: 0.1,
+            patience: 10,
+            threshold: 1e-4,
+            cooldown: 0,
+            min_lr: 0.0,
+            best: match mode {
+                PlateauMode::Min => f64::INFINITY,
+                PlateauMode::Max => f64::NEG_INFINITY,
+            },
+            bad_steps: 0,
+            cooldown_left: 0,
+        }
+    }
+
+    pub fn factor(mut self, factor: f64) -> ReduceLrOnPlateau {
+        assert!(factor < 1.0, "factor must be below 1");
+        self.factor = factor;
+        self
+    }
+
+    pub fn patience(mut self, patience: usize) -> ReduceLrOnPlateau {
+        self.patience = patience;
+        self
+    }
+
+    /// Relative change needed to count as an improvement.
+    pub fn threshold(mut self, threshold: f64) -> ReduceLrOnPlateau {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Steps to wait after a reduction before counting bad steps again.
+    pub fn cooldown(mut self, cooldown: usize) -> ReduceLrOnPlateau {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn min_lr(mut self, min_lr: f64) -> ReduceLrOnPlateau {
+        self.min_lr = min_lr;
+        self
+    }
+
+    pub fn lr(&self) -> f64 {
+        self.lr
+    }
+
+    pub fn best(&self) -> f64 {
+        self.best
+    }
+
+    fn is_better(&self, metric: f64) -> bool {
+        let margin = if self.best.is_finite() {
+            self.best.abs() * self.threshold
+        } else {
+            0.0
+        };
+
+        match self.mode {
+            PlateauMode::Min => metric < self.best - margin,
+            PlateauMode::Max => metric > self.best + margin,
+        }
+    }
+
+    /// Reports the metric for this step and updates the optimizer's learning
+    /// rate.
+    pub fn step(&mut self, metric: f64, optimizer: &mut dyn Optimizer) {
+        if self.is_better(metric) {
+            self.best = metric;
+            self.bad_steps = 0;
+        } else {
+            self.bad_steps += 1;
+        }
+
+        if self.cooldown_left > 0 {
+            self.cooldown_left -= 1;
+            self.bad_steps = 0;
+        }
+
+        if self.bad_steps > self.patience {
+            let lr = (self.lr * self.factor).max(self.min_lr);
+            if lr < self.lr {
+                log::info!("reducing learning rate to {}", lr);
+                self.lr = lr;
+            }
+            self.cooldown_left = self.cooldown;
+            self.bad_steps = 0;
+        }
+
+        optimizer.set_learning_rate(self.lr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sgd;
+    use crate::Value;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+    }
+
+    fn lrs(scheduler: &mut dyn LrScheduler, steps: usize) -> Vec<f64> {
+        let mut lrs = vec![scheduler.lr()];
+        for _ in 1..steps {
+            scheduler.advance();
+            lrs.push(scheduler.lr());
+        }
+        lrs
+    }
+
+    #[test]
+    fn test_step_lr() {
+        let lrs = lrs(&mut StepLr::new(1.0, 2, 0.5), 5);
+        assert_eq!(lrs, vec![1.0, 1.0, 0.5, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn test_exponential_lr() {
+        let lrs = lrs(&mut ExponentialLr::new(1.0, 0.5), 3);
+        assert_eq!(lrs, vec![1.0, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn test_cosine_warm_restarts() {
+        let lrs = lrs(&mut CosineAnnealingWarmRestarts::new(1.0, 2, 2, 0.0), 7);
+        assert_close(lrs[0], 1.0);
+        assert_close(lrs[1], 0.5);
+        // restart after t0 = 2 steps, next period is 4 steps
+        assert_close(lrs[2], 1.0);
+        assert_close(lrs[4], 0.5);
+        assert_close(lrs[6], 1.0);
+    }
+
+    #[test]
+    fn test_linear_warmup() {
+        let mut warmup = LinearWarmup::new(1.0, 4).then(StepLr::new(1.0, 1, 0.1));
+        let lrs = lrs(&mut warmup, 7);
+        assert_close(lrs[0], 0.0);
+        assert_close(lrs[2], 0.5);
+        assert_close(lrs[4], 1.0);
+        assert_close(lrs[5], 0.1);
+        assert_close(lrs[6], 0.01);
+    }
+
+    #[test]
+    fn test_one_cycle() {
+        let lrs = lrs(&mut OneCycleLr::new(1.0, 11).pct_start(0.3), 13);
+        assert_close(lrs[0], 0.04);
+        assert_close(lrs[3], 1.0);
+        assert!(lrs[1] < lrs[2] && lrs[4] < lrs[3]);
+        assert_close(lrs[10], 0.04 / 1e4);
+        assert_close(lrs[12], 0.04 / 1e4);
+    }
+
+    #[test]
+    fn test_reduce_on_plateau() {
+        let x = Value::new(0.0);
+        let mut sgd = Sgd::new(vec![&x], 1.0);
+        let mut plateau = ReduceLrOnPlateau::new(1.0, PlateauMode::Min)
+            .patience(1)
+            .factor(0.5);
+
+        for loss in [1.0, 0.5, 0.6, 0.6] {
+            plateau.step(loss, &mut sgd);
+        }
+        assert_close(sgd.learning_rate(), 0.5);
+        assert_close(plateau.best(), 0.5);
+
+        plateau.step(0.1, &mut sgd);
+        plateau.step(0.1, &mut sgd);
+        assert_close(sgd.learning_rate(), 0.5);
+    }
+
+    #[test]
+    fn test_step_sets_learning_rate() {
+        let x = Value::new(0.0);
+        let mut sgd = Sgd::new(vec![&x], 1.0);
+        let mut scheduler = ExponentialLr::new(1.0, 0.1);
+        scheduler.step(&mut sgd);
+        assert_close(sgd.learning_rate(), 0.1);
+    }
+}
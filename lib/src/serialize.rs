@@ -0,0 +1,553 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Activation;
+use crate::Layer;
+use crate::Neuron;
+use crate::MLP;
+
+/// Version written by `save`; files with a newer version are rejected.
+pub const FORMAT_VERSION: u32 = 1;
+
+const FORMAT_NAME: &str = "microml-mlp";
+const MAGIC: &[u8; 4] = b"MMLP";
+
+#[derive(Debug)]
+pub enum ModelError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    InvalidFormat(String),
+    UnsupportedVersion(u32),
+    /// A parameter in the file does not fit the model, e.g. `layers.1.weight`
+    /// saved as `[10, 64]` loaded into a model that has `[10, 32]`.
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    ActivationMismatch {
+        layer: usize,
+        expected: Activation,
+        found: Activation,
+    },
+    /// The model has a parameter tensor the file does not contain.
+    MissingTensor(String),
+    /// A NaN or infinite parameter, which JSON cannot represent, found while
+    /// saving.
+    NonFiniteValue {
+        name: String,
+        value: f64,
+    },
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Io(e) => write!(f, "io error: {}", e),
+            ModelError::Json(e) => write!(f, "json error: {}", e),
+            ModelError::InvalidFormat(msg) => write!(f, "invalid model file: {}", msg),
+            ModelError::UnsupportedVersion(v) => {
+                write!(f, "unsupported format version {}, expected at most {}", v, FORMAT_VERSION)
+            },
+            ModelError::ShapeMismatch { name, expected, found } => {
+                write!(f, "shape mismatch for {}: expected {:?}, found {:?}", name, expected, found)
+            },
+            ModelError::ActivationMismatch { layer, expected, found } => {
+                write!(f, "activation mismatch for layer {}: expected {:?}, found {:?}", layer, expected, found)
+            },
+            ModelError::MissingTensor(name) => write!(f, "missing tensor {}", name),
+            ModelError::NonFiniteValue { name, value } => write!(f, "cannot save non-finite value {} in {}", value, name),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ModelError::Io(e) => Some(e),
+            ModelError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ModelError {
+    fn from(e: io::Error) -> Self {
+        ModelError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ModelError {
+    fn from(e: serde_json::Error) -> Self {
+        ModelError::Json(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerSpec {
+    pub activation: Activation,
+    /// `[output_size, input_size]`, one row per neuron.
+    pub weights: Vec<Vec<f64>>,
+    pub bias: Vec<f64>,
+}
+
+/// Architecture and weights of a model, the in-memory form of both file
+/// formats.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelSpec {
+    pub format: String,
+    pub version: u32,
+    pub input_size: usize,
+    pub layers: Vec<LayerSpec>,
+}
+
+fn activation_tag(activation: Activation) -> (u8, f64) {
+    match activation {
+        Activation::Identity => (0, 0.0),
+        Activation::Relu => (1, 0.0),
+        Activation::LeakyRelu(slope) => (2, slope),
+        Activation::Elu(alpha) => (3, alpha),
+        Activation::Gelu => (4, 0.0),
+        Activation::Silu => (5, 0.0),
+        Activation::Tanh => (6, 0.0),
+        Activation::Sigmoid => (7, 0.0),
+        Activation::Softplus => (8, 0.0),
+    }
+}
+
+fn activation_from_tag(tag: u8, param: f64) -> Result<Activation, ModelError> {
+    Ok(match tag {
+        0 => Activation::Identity,
+        1 => Activation::Relu,
+        2 => Activation::LeakyRelu(param),
+        3 => Activation::Elu(param),
+        4 => Activation::Gelu,
+        5 => Activation::Silu,
+        6 => Activation::Tanh,
+        7 => Activation::Sigmoid,
+        8 => Activation::Softplus,
+        _ => return Err(ModelError::InvalidFormat(format!("unknown activation tag {}", tag))),
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ModelError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(ModelError::InvalidFormat("unexpected end of data".to_string()));
+        }
+        let out = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, ModelError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ModelError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, ModelError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn f64s(&mut self, n: usize) -> Result<Vec<f64>, ModelError> {
+        (0..n).map(|_| self.f64()).collect()
+    }
+}
+
+impl ModelSpec {
+    pub fn new(input_size: usize, layers: Vec<LayerSpec>) -> ModelSpec {
+        ModelSpec {
+            format: FORMAT_NAME.to_string(),
+            version: FORMAT_VERSION,
+            input_size,
+            layers,
+        }
+    }
+
+    /// Checks the format header and that every layer's weights fit the
+    /// previous layer's output.
+    pub fn validate(&self) -> Result<(), ModelError> {
+        if self.format != FORMAT_NAME {
+            return Err(ModelError::InvalidFormat(format!("unknown format {:?}", self.format)));
+        }
+        if self.version == 0 || self.version > FORMAT_VERSION {
+            return Err(ModelError::UnsupportedVersion(self.version));
+        }
+
+        let mut input_size = self.input_size;
+        for (l, layer) in self.layers.iter().enumerate() {
+            for (o, row) in layer.weights.iter().enumerate() {
+                if row.len() != input_size {
+                    return Err(ModelError::ShapeMismatch {
+                        name: format!("layers.{}.weight.{}", l, o),
+                        expected: vec![input_size],
+                        found: vec![row.len()],
+                    });
+                }
+            }
+            if layer.bias.len() != layer.weights.len() {
+                return Err(ModelError::ShapeMismatch {
+                    name: format!("layers.{}.bias", l),
+                    expected: vec![layer.weights.len()],
+                    found: vec![layer.bias.len()],
+                });
+            }
+            input_size = layer.weights.len();
+        }
+
+        Ok(())
+    }
+
+    /// Fails on the first NaN or infinite weight, e.g. from a diverged run.
+    pub fn check_finite(&self) -> Result<(), ModelError> {
+        for (l, layer) in self.layers.iter().enumerate() {
+            for (o, row) in layer.weights.iter().enumerate() {
+                if let Some((i, &value)) = row.iter().enumerate().find(|(_, x)| !x.is_finite()) {
+                    return Err(ModelError::NonFiniteValue { name: format!("layers.{}.weight.{}.{}", l, o, i), value });
+                }
+            }
+            if let Some((o, &value)) = layer.bias.iter().enumerate().find(|(_, x)| !x.is_finite()) {
+                return Err(ModelError::NonFiniteValue { name: format!("layers.{}.bias.{}", l, o), value });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String, ModelError> {
+        self.check_finite()?;
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<ModelSpec, ModelError> {
+        let spec: ModelSpec = serde_json::from_str(json)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Little endian: magic, version, input size and layer count, then per
+    /// layer the output size, activation and the weights followed by the
+    /// biases as `f64`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ModelError> {
+        let size = |what: &str, n: usize| {
+            u32::try_from(n).map_err(|_| ModelError::InvalidFormat(format!("{} {} does not fit the binary format", what, n)))
+        };
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.extend_from_slice(&size("input size", self.input_size)?.to_le_bytes());
+        out.extend_from_slice(&size("layer count", self.layers.len())?.to_le_bytes());
+
+        for layer in &self.layers {
+            let (tag, param) = activation_tag(layer.activation);
+            out.extend_from_slice(&size("layer size", layer.weights.len())?.to_le_bytes());
+            out.push(tag);
+            out.extend_from_slice(&param.to_le_bytes());
+            for x in layer.weights.iter().flatten().chain(layer.bias.iter()) {
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ModelSpec, ModelError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(ModelError::InvalidFormat("missing MMLP header".to_string()));
+        }
+        let version = reader.u32()?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(ModelError::UnsupportedVersion(version));
+        }
+        let input_size = reader.u32()? as usize;
+        let layer_count = reader.u32()? as usize;
+
+        let mut layers = Vec::new();
+        let mut fan_in = input_size;
+        for _ in 0..layer_count {
+            let output_size = reader.u32()? as usize;
+            let activation = activation_from_tag(reader.u8()?, reader.f64()?)?;
+            // Check the sizes against the data before allocating for them.
+            let needed = fan_in.checked_add(1)
+                .and_then(|n| n.checked_mul(output_size))
+                .and_then(|n| n.checked_mul(8));
+            if needed.map_or(true, |n| n > reader.remaining()) {
+                return Err(ModelError::InvalidFormat(format!("layer of {} x {} exceeds the data", output_size, fan_in)));
+            }
+            let weights = (0..output_size)
+                .map(|_| reader.f64s(fan_in))
+                .collect::<Result<Vec<_>, _>>()?;
+            let bias = reader.f64s(output_size)?;
+            layers.push(LayerSpec { activation, weights, bias });
+            fan_in = output_size;
+        }
+
+        if reader.pos != bytes.len() {
+            return Err(ModelError::InvalidFormat(format!("{} trailing bytes", bytes.len() - reader.pos)));
+        }
+
+        let spec = ModelSpec { version, ..ModelSpec::new(input_size, layers) };
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Reads either format, binary files are recognized by their header.
+    pub fn read(path: impl AsRef<Path>) -> Result<ModelSpec, ModelError> {
+        let bytes = fs::read(path)?;
+        if bytes.starts_with(MAGIC) {
+            ModelSpec::from_bytes(&bytes)
+        } else {
+            let json = std::str::from_utf8(&bytes)
+                .map_err(|e| ModelError::InvalidFormat(e.to_string()))?;
+            ModelSpec::from_json(json)
+        }
+    }
+
+    /// Writes JSON for a `.json` path and the binary format otherwise. Both
+    /// refuse NaN or infinite weights rather than save a broken model.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), ModelError> {
+        self.check_finite()?;
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            fs::write(path, self.to_json()?)?;
+        } else {
+            fs::write(path, self.to_bytes()?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Saving and loading of a model's architecture and weights.
+pub trait Persist: Sized {
+    fn to_spec(&self) -> ModelSpec;
+
+    /// Builds a new model with the architecture and weights of `spec`.
+    fn from_spec(spec: &ModelSpec) -> Result<Self, ModelError>;
+
+    /// Copies the weights of `spec` into this model, which must have the same
+    /// architecture.
+    fn load_spec(&self, spec: &ModelSpec) -> Result<(), ModelError>;
+
+    /// Saves as JSON for a `.json` path and in the binary format otherwise.
+    fn save(&self, path: impl AsRef<Path>) -> Result<(), ModelError> {
+        self.to_spec().write(path)
+    }
+
+    fn load(path: impl AsRef<Path>) -> Result<Self, ModelError> {
+        Self::from_spec(&ModelSpec::read(path)?)
+    }
+
+    /// Loads saved weights into an existing model, keeping the parameter
+    /// `Value`s so optimizers holding them stay valid.
+    fn load_weights(&self, path: impl AsRef<Path>) -> Result<(), ModelError> {
+        self.load_spec(&ModelSpec::read(path)?)
+    }
+}
+
+fn layer_spec(layer: &Layer) -> LayerSpec {
+    LayerSpec {
+        activation: layer.activation(),
+        weights: layer.neurons()
+            .iter()
+            .map(|n| n.weights().iter().map(|w| w.data()).collect())
+            .collect(),
+        bias: layer.neurons().iter().map(|n| n.bias().data()).collect(),
+    }
+}
+
+fn layer_from_spec(spec: &LayerSpec) -> Layer {
+    let neurons = spec.weights.iter()
+        .zip(spec.bias.iter())
+        .map(|(weights, &bias)| Neuron::from_weights(weights, bias, spec.activation))
+        .collect();
+
+    Layer::from_neurons(neurons)
+}
+
+fn load_layer(l: usize, layer: &Layer, spec: &LayerSpec) -> Result<(), ModelError> {
+    let expected = [layer.neurons().len(), layer.neurons().first().map_or(0, |n| n.weights().len())];
+    let found = [spec.weights.len(), spec.weights.first().map_or(0, |row| row.len())];
+    if expected != found {
+        return Err(ModelError::ShapeMismatch {
+            name: format!("layers.{}.weight", l),
+            expected: expected.to_vec(),
+            found: found.to_vec(),
+        });
+    }
+    if layer.activation() != spec.activation {
+        return Err(ModelError::ActivationMismatch {
+            layer: l,
+            expected: layer.activation(),
+            found: spec.activation,
+        });
+    }
+
+    for (neuron, (weights, &bias)) in layer.neurons().iter().zip(spec.weights.iter().zip(spec.bias.iter())) {
+        for (w, &x) in neuron.weights().iter().zip(weights) {
+            w.set_data(x);
+        }
+        neuron.bias().set_data(bias);
+    }
+
+    Ok(())
+}
+
+impl Persist for MLP {
+    fn to_spec(&self) -> ModelSpec {
+        let input_size = self.layers()
+            .first()
+            .and_then(|l| l.neurons().first())
+            .map_or(0, |n| n.weights().len());
+
+        ModelSpec::new(input_size, self.layers().iter().map(layer_spec).collect())
+    }
+
+    fn from_spec(spec: &ModelSpec) -> Result<Self, ModelError> {
+        spec.validate()?;
+        Ok(MLP::from_layers(spec.layers.iter().map(layer_from_spec).collect()))
+    }
+
+    fn load_spec(&self, spec: &ModelSpec) -> Result<(), ModelError> {
+        spec.validate()?;
+        if spec.layers.len() != self.layers().len() {
+            return Err(ModelError::ShapeMismatch {
+                name: "layers".to_string(),
+                expected: vec![self.layers().len()],
+                found: vec![spec.layers.len()],
+            });
+        }
+
+        for (l, (layer, layer_spec)) in self.layers().iter().zip(spec.layers.iter()).enumerate() {
+            load_layer(l, layer, layer_spec)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+
+    fn model() -> MLP {
+        MLP::builder(3)
+            .layer(4, Activation::LeakyRelu(0.1))
+            .layer(2, Activation::Identity)
+            .build()
+    }
+
+    fn data(model: &MLP) -> Vec<f64> {
+        model.parameters().iter().map(|p| p.data()).collect()
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mlp = model();
+        let json = mlp.to_spec().to_json().unwrap();
+        let loaded = MLP::from_spec(&ModelSpec::from_json(&json).unwrap()).unwrap();
+
+        assert_eq!(data(&loaded), data(&mlp));
+        assert_eq!(loaded.layers()[0].activation(), Activation::LeakyRelu(0.1));
+        assert!(json.contains("\"version\": 1"));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mlp = model();
+        let bytes = mlp.to_spec().to_bytes().unwrap();
+        assert_eq!(bytes.len(), 16 + 2 * 13 + 8 * (3 * 4 + 4 + 4 * 2 + 2));
+
+        let spec = ModelSpec::from_bytes(&bytes).unwrap();
+        assert_eq!(spec, mlp.to_spec());
+
+        let mut truncated = bytes.clone();
+        truncated.pop();
+        assert!(matches!(ModelSpec::from_bytes(&truncated), Err(ModelError::InvalidFormat(_))));
+
+        let mut future = bytes;
+        future[4] = 2;
+        assert!(matches!(ModelSpec::from_bytes(&future), Err(ModelError::UnsupportedVersion(2))));
+
+        // One layer of u32::MAX outputs on no inputs, rejected before allocating.
+        let mut huge = MAGIC.to_vec();
+        for x in [1u32, 0, 1, u32::MAX] {
+            huge.extend_from_slice(&x.to_le_bytes());
+        }
+        huge.push(0);
+        huge.extend_from_slice(&0f64.to_le_bytes());
+        assert!(matches!(ModelSpec::from_bytes(&huge), Err(ModelError::InvalidFormat(_))));
+
+        let wide = ModelSpec::new(1 << 33, vec![]);
+        assert!(matches!(wide.to_bytes(), Err(ModelError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_save_and_load_files() {
+        // The process id keeps concurrent test runs from sharing files.
+        let dir = std::env::temp_dir();
+        let mlp = model();
+
+        for ext in ["json", "bin"] {
+            let path = dir.join(format!("microml_test_model_{}.{}", std::process::id(), ext));
+            mlp.save(&path).unwrap();
+            let loaded = MLP::load(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(data(&loaded), data(&mlp));
+        }
+    }
+
+    #[test]
+    fn test_rejects_non_finite_weights() {
+        let mlp = model();
+        mlp.layers()[1].neurons()[0].weights()[2].set_data(f64::NAN);
+
+        let err = mlp.to_spec().to_json().unwrap_err();
+        assert!(matches!(&err, ModelError::NonFiniteValue { name, .. } if name == "layers.1.weight.0.2"));
+        assert_eq!(err.to_string(), "cannot save non-finite value NaN in layers.1.weight.0.2");
+
+        let path = std::env::temp_dir().join(format!("microml_test_nan_{}.bin", std::process::id()));
+        assert!(matches!(mlp.save(&path), Err(ModelError::NonFiniteValue { .. })));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_load_weights_checks_shapes() {
+        let mlp = model();
+        let other = model();
+        other.load_spec(&mlp.to_spec()).unwrap();
+        assert_eq!(data(&other), data(&mlp));
+
+        let wider = MLP::builder(3)
+            .layer(5, Activation::LeakyRelu(0.1))
+            .layer(2, Activation::Identity)
+            .build();
+        let err = wider.load_spec(&mlp.to_spec()).unwrap_err();
+        assert!(matches!(&err, ModelError::ShapeMismatch { name, expected, found }
+            if name == "layers.0.weight" && expected == &[5, 3] && found == &[4, 3]));
+        assert_eq!(err.to_string(), "shape mismatch for layers.0.weight: expected [5, 3], found [4, 3]");
+
+        let mut spec = mlp.to_spec();
+        spec.layers[1].weights[0].pop();
+        assert!(matches!(ModelSpec::from_json(&spec.to_json().unwrap()), Err(ModelError::ShapeMismatch { .. })));
+    }
+}
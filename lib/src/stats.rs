@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Value;
+use crate::value::NODE_BYTES;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphStats {
+    /// Distinct nodes reachable from the value, including itself.
+    pub node_count: usize,
+    /// Nodes without an op: parameters, inputs and constants.
+    pub leaf_count: usize,
+    /// Number of nodes per op, most frequent first.
+    pub op_counts: Vec<(&'static str, usize)>,
+    /// Longest chain of ops from a leaf to the value.
+    pub depth: usize,
+    /// Approximate memory held by the nodes.
+    pub bytes: usize,
+}
+
+impl GraphStats {
+    pub fn op_count(&self, op: &str) -> usize {
+        self.op_counts.iter().find(|(name, _)| *name == op).map_or(0, |(_, count)| *count)
+    }
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes ({} leaves), depth {}, ~{:.1} KiB",
+            self.node_count,
+            self.leaf_count,
+            self.depth,
+            self.bytes as f64 / 1024.0
+        )?;
+        for (op, count) in &self.op_counts {
+            writeln!(f, "  {:<10} {}", op, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Value {
+    /// Walks the graph behind this value and summarizes its size.
+    pub fn graph_stats(&self) -> GraphStats {
+        let nodes = self.graph_nodes(None, None);
+        let index = nodes.iter()
+            .enumerate()
+            .map(|(i, n)| (n.id, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut histogram: HashMap<&'static str, usize> = HashMap::new();
+        for op in nodes.iter().filter_map(|n| n.op) {
+            *histogram.entry(op).or_insert(0) += 1;
+        }
+        let mut op_counts = histogram.into_iter().collect::<Vec<_>>();
+        op_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        // Longest path, parents before children with an explicit stack since
+        // graphs like a long sum are too deep to recurse.
+        let mut heights: Vec<Option<usize>> = vec![None; nodes.len()];
+        let mut stack = vec![(0, false)];
+        while let Some((i, expanded)) = stack.pop() {
+            if expanded {
+                heights[i] = Some(nodes[i].parents.iter()
+                    .map(|p| heights[index[p]].unwrap() + 1)
+                    .max()
+                    .unwrap_or(0));
+                continue;
+            }
+            if heights[i].is_some() {
+                continue;
+            }
+
+            stack.push((i, true));
+            for p in &nodes[i].parents {
+                if heights[index[p]].is_none() {
+                    stack.push((index[p], false));
+                }
+            }
+        }
+
+        GraphStats {
+            node_count: nodes.len(),
+            leaf_count: nodes.iter().filter(|n| n.op.is_none()).count(),
+            op_counts,
+            depth: heights[0].unwrap_or(0),
+            bytes: nodes.len() * NODE_BYTES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Activation;
+    use crate::Module;
+    use crate::Neuron;
+
+    #[test]
+    fn test_small_graph() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let c = (&a * &b + &a).tanh();
+
+        let stats = c.graph_stats();
+        assert_eq!(stats.node_count, 5);
+        assert_eq!(stats.leaf_count, 2);
+        assert_eq!(stats.depth, 3);
+        assert_eq!(stats.op_count("Mul"), 1);
+        assert_eq!(stats.op_count("Tanh"), 1);
+        assert_eq!(stats.op_count("Div"), 0);
+        assert_eq!(stats.bytes, 5 * NODE_BYTES);
+
+        let leaf = a.graph_stats();
+        assert_eq!((leaf.node_count, leaf.leaf_count, leaf.depth), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_neuron_graph() {
+        let n = 100;
+        let neuron = Neuron::from_weights(&vec![0.1; n], 0.5, Activation::Relu);
+        let input = (0..n).map(|i| Value::new(i as f64)).collect::<Vec<_>>();
+        let out = neuron.forward(&input);
+
+        let stats = out.graph_stats();
+        // Weights, inputs, the bias, the zero the sum starts from and the zero
+        // relu compares against.
+        assert_eq!(stats.leaf_count, 2 * n + 3);
+        assert_eq!(stats.op_counts[0], ("Add", n + 1));
+        assert_eq!(stats.op_count("Mul"), n);
+        assert_eq!(stats.op_count("Max"), 1);
+        assert_eq!(stats.node_count, stats.leaf_count + 2 * n + 2);
+        // Mul, the n adds of the sum, the bias add and relu.
+        assert_eq!(stats.depth, n + 3);
+        assert_eq!(neuron.parameters().len(), n + 1);
+    }
+}
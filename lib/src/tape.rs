@@ -0,0 +1,483 @@
+use crate::Activation;
+use crate::Value;
+
+/// Handle to a matrix recorded on a `Tape`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Var(usize);
+
+#[derive(Debug)]
+enum TapeOp {
+    Leaf,
+    /// `x @ w^T + b` with `w` in `[out, in]` layout like `Layer`.
+    Linear { x: Var, w: Var, b: Var },
+    MatMul(Var, Var),
+    /// Elementwise add, `right` may also be a single row broadcast over `left`.
+    Add(Var, Var),
+    Mul(Var, Var),
+    Scale(Var, f64),
+    Relu(Var),
+    Tanh(Var),
+    Sigmoid(Var),
+    Activation(Var, Activation),
+    Sum(Var),
+    Mean(Var),
+    /// Mean softmax cross entropy over rows. Keeps the softmax for backward.
+    CrossEntropy { logits: Var, targets: Vec<usize>, probs: Vec<f64> },
+    Mse(Var, Var),
+}
+
+#[derive(Debug)]
+struct Node {
+    op: TapeOp,
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+    grad: Vec<f64>,
+}
+
+/// A Wengert list of matrix operations over contiguous `f64` buffers.
+///
+/// Every operation appends a node, so the tape is already in topological
+/// order and `backward` is a single reverse sweep without any per-scalar
+/// allocations. Parameters recorded with `param` are bound to their `Value`s
+/// and receive their gradients on `backward`, so the usual update code keeps
+/// working.
+#[derive(Debug, Default)]
+pub struct Tape {
+    nodes: Vec<Node>,
+    bindings: Vec<(Var, Vec<Value>)>,
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape::default()
+    }
+
+    /// Drops all recorded nodes so the tape can be reused for the next batch.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.bindings.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn push(&mut self, op: TapeOp, rows: usize, cols: usize, data: Vec<f64>) -> Var {
+        debug_assert_eq!(data.len(), rows * cols);
+        self.nodes.push(Node {
+            op,
+            rows,
+            cols,
+            grad: vec![0.0; data.len()],
+            data,
+        });
+        Var(self.nodes.len() - 1)
+    }
+
+    pub fn leaf(&mut self, data: Vec<f64>, rows: usize, cols: usize) -> Var {
+        assert_eq!(data.len(), rows * cols, "{} values do not fit {}x{}", data.len(), rows, cols);
+        self.push(TapeOp::Leaf, rows, cols, data)
+    }
+
+    /// Records `values` as a leaf whose gradient is added to the values'
+    /// gradients on `backward`.
+    pub fn param(&mut self, values: Vec<Value>, rows: usize, cols: usize) -> Var {
+        let var = self.leaf(values.iter().map(|v| v.data()).collect(), rows, cols);
+        self.bindings.push((var, values));
+        var
+    }
+
+    pub fn shape(&self, var: Var) -> (usize, usize) {
+        let node = &self.nodes[var.0];
+        (node.rows, node.cols)
+    }
+
+    pub fn value(&self, var: Var) -> &[f64] {
+        &self.nodes[var.0].data
+    }
+
+    pub fn grad(&self, var: Var) -> &[f64] {
+        &self.nodes[var.0].grad
+    }
+
+    /// Index of the largest element in each row.
+    pub fn argmax(&self, var: Var) -> Vec<usize> {
+        let node = &self.nodes[var.0];
+        node.data.chunks(node.cols)
+            .map(|row| {
+                row.iter()
+                    .enumerate()
+                    .fold((0, f64::MIN), |best, (i, &x)| if x > best.1 { (i, x) } else { best })
+                    .0
+            })
+            .collect()
+    }
+
+    pub fn linear(&mut self, x: Var, w: Var, b: Var) -> Var {
+        let (rows, inputs) = self.shape(x);
+        let (outputs, w_inputs) = self.shape(w);
+        assert_eq!(inputs, w_inputs, "linear input has {} columns but weights expect {}", inputs, w_inputs);
+        assert_eq!(self.value(b).len(), outputs, "linear bias does not match {} outputs", outputs);
+
+        let xs = self.value(x);
+        let ws = self.value(w);
+        let bs = self.value(b);
+        let mut data = Vec::with_capacity(rows * outputs);
+        for row in xs.chunks(inputs) {
+            for (o, weights) in ws.chunks(inputs).enumerate() {
+                data.push(bs[o] + row.iter().zip(weights).map(|(x, w)| x * w).sum::<f64>());
+            }
+        }
+
+        self.push(TapeOp::Linear { x, w, b }, rows, outputs, data)
+    }
+
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let (m, k) = self.shape(a);
+        let (k2, n) = self.shape(b);
+        assert_eq!(k, k2, "can not matmul {}x{} with {}x{}", m, k, k2, n);
+
+        let av = self.value(a);
+        let bv = self.value(b);
+        let mut data = vec![0.0; m * n];
+        for i in 0..m {
+            for l in 0..k {
+                let a_il = av[i * k + l];
+                for j in 0..n {
+                    data[i * n + j] += a_il * bv[l * n + j];
+                }
+            }
+        }
+
+        self.push(TapeOp::MatMul(a, b), m, n, data)
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let (rows, cols) = self.shape(a);
+        let bv = self.value(b);
+        assert!(
+            bv.len() == rows * cols || bv.len() == cols,
+            "can not add {:?} to {}x{}", self.shape(b), rows, cols
+        );
+
+        let data = self.value(a).iter()
+            .enumerate()
+            .map(|(i, x)| x + bv[i % bv.len()])
+            .collect();
+        self.push(TapeOp::Add(a, b), rows, cols, data)
+    }
+
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        let (rows, cols) = self.shape(a);
+        assert_eq!(self.shape(a), self.shape(b), "can not multiply different shapes");
+
+        let data = self.value(a).iter().zip(self.value(b)).map(|(x, y)| x * y).collect();
+        self.push(TapeOp::Mul(a, b), rows, cols, data)
+    }
+
+    pub fn scale(&mut self, a: Var, factor: f64) -> Var {
+        let (rows, cols) = self.shape(a);
+        let data = self.value(a).iter().map(|x| x * factor).collect();
+        self.push(TapeOp::Scale(a, factor), rows, cols, data)
+    }
+
+    fn map(&mut self, a: Var, op: TapeOp, f: impl Fn(f64) -> f64) -> Var {
+        let (rows, cols) = self.shape(a);
+        let data = self.value(a).iter().map(|&x| f(x)).collect();
+        self.push(op, rows, cols, data)
+    }
+
+    pub fn relu(&mut self, a: Var) -> Var {
+        self.map(a, TapeOp::Relu(a), |x| x.max(0.0))
+    }
+
+    pub fn tanh(&mut self, a: Var) -> Var {
+        self.map(a, TapeOp::Tanh(a), f64::tanh)
+    }
+
+    pub fn sigmoid(&mut self, a: Var) -> Var {
+        self.map(a, TapeOp::Sigmoid(a), |x| 1.0 / (1.0 + (-x).exp()))
+    }
+
+    pub fn activation(&mut self, a: Var, activation: Activation) -> Var {
+        if activation == Activation::Identity {
+            return a;
+        }
+        self.map(a, TapeOp::Activation(a, activation), |x| activation.apply_f64(x))
+    }
+
+    pub fn sum(&mut self, a: Var) -> Var {
+        let sum = self.value(a).iter().sum();
+        self.push(TapeOp::Sum(a), 1, 1, vec![sum])
+    }
+
+    pub fn mean(&mut self, a: Var) -> Var {
+        let values = self.value(a);
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        self.push(TapeOp::Mean(a), 1, 1, vec![mean])
+    }
+
+    /// Mean cross entropy between the softmax of each row of `logits` and the
+    /// target class of that row.
+    pub fn cross_entropy(&mut self, logits: Var, targets: &[usize]) -> Var {
+        let (rows, cols) = self.shape(logits);
+        assert_eq!(rows, targets.len(), "{} rows but {} targets", rows, targets.len());
+
+        let mut probs = Vec::with_capacity(rows * cols);
+        let mut loss = 0.0;
+        for (row, &target) in self.value(logits).chunks(cols).zip(targets) {
+            assert!(target < cols, "target {} out of range for {} classes", target, cols);
+            let max = row.iter().cloned().fold(f64::MIN, f64::max);
+            let sum = row.iter().map(|x| (x - max).exp()).sum::<f64>();
+            loss += sum.ln() + max - row[target];
+            probs.extend(row.iter().map(|x| (x - max).exp() / sum));
+        }
+
+        let op = TapeOp::CrossEntropy { logits, targets: targets.to_vec(), probs };
+        self.push(op, 1, 1, vec![loss / rows as f64])
+    }
+
+    pub fn mse(&mut self, prediction: Var, target: Var) -> Var {
+        assert_eq!(self.shape(prediction), self.shape(target), "mse shapes differ");
+        let p = self.value(prediction);
+        let t = self.value(target);
+        let loss = p.iter().zip(t).map(|(p, t)| (p - t).powi(2)).sum::<f64>() / p.len() as f64;
+        self.push(TapeOp::Mse(prediction, target), 1, 1, vec![loss])
+    }
+
+    /// Backpropagates from the scalar `var` and adds the gradients of every
+    /// bound parameter to its `Value`.
+    pub fn backward(&mut self, var: Var) {
+        assert_eq!(self.value(var).len(), 1, "backward needs a scalar, got {:?}", self.shape(var));
+
+        for node in self.nodes.iter_mut() {
+            node.grad.iter_mut().for_each(|g| *g = 0.0);
+        }
+        self.nodes[var.0].grad[0] = 1.0;
+
+        for i in (0..=var.0).rev() {
+            let (before, after) = self.nodes.split_at_mut(i);
+            let node = &after[0];
+            let g = &node.grad;
+
+            match &node.op {
+                TapeOp::Leaf => {},
+                TapeOp::Linear { x, w, b } => {
+                    let inputs = before[x.0].cols;
+                    let outputs = node.cols;
+                    let mut dx = vec![0.0; before[x.0].data.len()];
+                    let mut dw = vec![0.0; before[w.0].data.len()];
+                    let mut db = vec![0.0; outputs];
+                    {
+                        let xs = &before[x.0].data;
+                        let ws = &before[w.0].data;
+                        for (r, g_row) in g.chunks(outputs).enumerate() {
+                            let x_row = &xs[r * inputs..(r + 1) * inputs];
+                            let dx_row = &mut dx[r * inputs..(r + 1) * inputs];
+                            for (o, &go) in g_row.iter().enumerate() {
+                                if go == 0.0 {
+                                    continue;
+                                }
+                                db[o] += go;
+                                let w_row = &ws[o * inputs..(o + 1) * inputs];
+                                let dw_row = &mut dw[o * inputs..(o + 1) * inputs];
+                                for k in 0..inputs {
+                                    dx_row[k] += go * w_row[k];
+                                    dw_row[k] += go * x_row[k];
+                                }
+                            }
+                        }
+                    }
+                    accumulate(before, *x, &dx);
+                    accumulate(before, *w, &dw);
+                    accumulate(before, *b, &db);
+                },
+                TapeOp::MatMul(a, b) => {
+                    let (m, k) = (before[a.0].rows, before[a.0].cols);
+                    let n = node.cols;
+                    let mut da = vec![0.0; m * k];
+                    let mut db = vec![0.0; k * n];
+                    {
+                        let av = &before[a.0].data;
+                        let bv = &before[b.0].data;
+                        for i in 0..m {
+                            for l in 0..k {
+                                for j in 0..n {
+                                    da[i * k + l] += g[i * n + j] * bv[l * n + j];
+                                    db[l * n + j] += g[i * n + j] * av[i * k + l];
+                                }
+                            }
+                        }
+                    }
+                    accumulate(before, *a, &da);
+                    accumulate(before, *b, &db);
+                },
+                TapeOp::Add(a, b) => {
+                    let width = before[b.0].data.len();
+                    let mut db = vec![0.0; width];
+                    for (i, g) in g.iter().enumerate() {
+                        db[i % width] += g;
+                    }
+                    accumulate(before, *a, g);
+                    accumulate(before, *b, &db);
+                },
+                TapeOp::Mul(a, b) => {
+                    let da = g.iter().zip(&before[b.0].data).map(|(g, y)| g * y).collect::<Vec<_>>();
+                    let db = g.iter().zip(&before[a.0].data).map(|(g, x)| g * x).collect::<Vec<_>>();
+                    accumulate(before, *a, &da);
+                    accumulate(before, *b, &db);
+                },
+                TapeOp::Scale(a, factor) => {
+                    let da = g.iter().map(|g| g * factor).collect::<Vec<_>>();
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::Relu(a) => {
+                    let da = g.iter().zip(&node.data).map(|(g, &y)| if y > 0.0 { *g } else { 0.0 }).collect::<Vec<_>>();
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::Tanh(a) => {
+                    let da = g.iter().zip(&node.data).map(|(g, y)| g * (1.0 - y * y)).collect::<Vec<_>>();
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::Sigmoid(a) => {
+                    let da = g.iter().zip(&node.data).map(|(g, y)| g * y * (1.0 - y)).collect::<Vec<_>>();
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::Activation(a, activation) => {
+                    let da = g.iter()
+                        .zip(&before[a.0].data)
+                        .map(|(g, &x)| g * activation.derivative_f64(x))
+                        .collect::<Vec<_>>();
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::Sum(a) => {
+                    let da = vec![g[0]; before[a.0].data.len()];
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::Mean(a) => {
+                    let n = before[a.0].data.len();
+                    let da = vec![g[0] / n as f64; n];
+                    accumulate(before, *a, &da);
+                },
+                TapeOp::CrossEntropy { logits, targets, probs } => {
+                    let cols = before[logits.0].cols;
+                    let scale = g[0] / targets.len() as f64;
+                    let mut da = probs.iter().map(|p| p * scale).collect::<Vec<_>>();
+                    for (row, &target) in targets.iter().enumerate() {
+                        da[row * cols + target] -= scale;
+                    }
+                    accumulate(before, *logits, &da);
+                },
+                TapeOp::Mse(p, t) => {
+                    let n = before[p.0].data.len() as f64;
+                    let dp = before[p.0].data.iter()
+                        .zip(&before[t.0].data)
+                        .map(|(p, t)| g[0] * 2.0 * (p - t) / n)
+                        .collect::<Vec<_>>();
+                    let dt = dp.iter().map(|d| -d).collect::<Vec<_>>();
+                    accumulate(before, *p, &dp);
+                    accumulate(before, *t, &dt);
+                },
+            }
+        }
+
+        for (var, values) in &self.bindings {
+            for (value, g) in values.iter().zip(&self.nodes[var.0].grad) {
+                value.add_grad(*g);
+            }
+        }
+    }
+}
+
+fn accumulate(nodes: &mut [Node], var: Var, grad: &[f64]) {
+    for (g, d) in nodes[var.0].grad.iter_mut().zip(grad) {
+        *g += d;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::softmax;
+    use crate::Module;
+    use crate::MLP;
+
+    #[test]
+    fn test_mlp_matches_value_engine() {
+        let mlp = MLP::builder(4)
+            .layer(5, Activation::Gelu)
+            .layer(3, Activation::Identity)
+            .build();
+        let samples = [[0.5, -1.0, 2.0, 0.1], [0.3, 0.2, -0.7, 1.5]];
+        let targets = [2, 0];
+
+        let mut tape = Tape::new();
+        let x = tape.leaf(samples.concat(), 2, 4);
+        let logits = mlp.forward_tape(&mut tape, x);
+        let loss = tape.cross_entropy(logits, &targets);
+        tape.backward(loss);
+        let tape_grads = mlp.parameters().iter().map(|p| p.grad()).collect::<Vec<_>>();
+        mlp.zero_grad();
+
+        let value_loss = samples.iter()
+            .zip(targets.iter())
+            .map(|(sample, &target)| {
+                let out = mlp.forward(sample.iter().map(|&x| Value::new(x)).collect());
+                -softmax(&out)[target].log()
+            })
+            .sum::<Value>()
+            / 2.0;
+        value_loss.backward();
+
+        assert!((tape.value(loss)[0] - value_loss.data()).abs() < 1e-9);
+        for (p, tape_grad) in mlp.parameters().iter().zip(tape_grads) {
+            assert!((p.grad() - tape_grad).abs() < 1e-9, "{} != {}", p.grad(), tape_grad);
+        }
+    }
+
+    #[test]
+    fn test_elementwise_grads() {
+        let a_data = vec![0.5, -1.0, 2.0, 0.3];
+        let b_data = vec![1.5, 0.2, -0.4, 0.9];
+        let loss_at = |a: Vec<f64>| {
+            let mut tape = Tape::new();
+            let a = tape.leaf(a, 2, 2);
+            let b = tape.leaf(b_data.clone(), 2, 2);
+            let m = tape.matmul(a, b);
+            let t = tape.tanh(m);
+            let s = tape.sigmoid(a);
+            let p = tape.mul(t, s);
+            let r = tape.relu(p);
+            let q = tape.scale(r, 3.0);
+            let l = tape.mse(q, b);
+            (tape, a, l)
+        };
+
+        let (mut tape, a, l) = loss_at(a_data.clone());
+        tape.backward(l);
+
+        let eps = 1e-6;
+        for i in 0..a_data.len() {
+            let mut plus = a_data.clone();
+            plus[i] += eps;
+            let mut minus = a_data.clone();
+            minus[i] -= eps;
+            let (tp, _, lp) = loss_at(plus);
+            let (tm, _, lm) = loss_at(minus);
+            let numeric = (tp.value(lp)[0] - tm.value(lm)[0]) / (2.0 * eps);
+            assert!((tape.grad(a)[i] - numeric).abs() < 1e-6, "{} != {}", tape.grad(a)[i], numeric);
+        }
+    }
+
+    #[test]
+    fn test_argmax() {
+        let mut tape = Tape::new();
+        let x = tape.leaf(vec![0.1, 0.7, 0.2, 0.9, -1.0, 0.0], 2, 3);
+        assert_eq!(tape.argmax(x), vec![1, 0]);
+    }
+}
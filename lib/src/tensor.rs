@@ -0,0 +1,487 @@
+use std::rc::Rc;
+
+use crate::Value;
+
+/// An n-dimensional array of `Value`s. Views such as `transpose`, `slice` and
+/// `broadcast_to` share the storage and only change shape, strides and offset,
+/// so gradients flow back to the original values.
+#[derive(Debug, Clone)]
+pub struct Tensor {
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+    offset: usize,
+    storage: Rc<Vec<Value>>,
+}
+
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+fn broadcast_shape(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let ndim = a.len().max(b.len());
+    let mut shape = Vec::with_capacity(ndim);
+    for i in 0..ndim {
+        let a_dim = if i + a.len() >= ndim { a[i + a.len() - ndim] } else { 1 };
+        let b_dim = if i + b.len() >= ndim { b[i + b.len() - ndim] } else { 1 };
+        assert!(
+            a_dim == b_dim || a_dim == 1 || b_dim == 1,
+            "shapes {:?} and {:?} can not be broadcast together", a, b
+        );
+        shape.push(a_dim.max(b_dim));
+    }
+    shape
+}
+
+impl Tensor {
+    pub fn new(values: Vec<Value>, shape: &[usize]) -> Tensor {
+        assert_eq!(
+            values.len(),
+            shape.iter().product::<usize>(),
+            "{} values do not fit shape {:?}", values.len(), shape
+        );
+
+        Tensor {
+            shape: shape.to_vec(),
+            strides: contiguous_strides(shape),
+            offset: 0,
+            storage: Rc::new(values),
+        }
+    }
+
+    pub fn from_f64(data: &[f64], shape: &[usize]) -> Tensor {
+        Tensor::new(data.iter().map(|&x| Value::new(x)).collect(), shape)
+    }
+
+    pub fn zeros(shape: &[usize]) -> Tensor {
+        Tensor::from_f64(&vec![0.0; shape.iter().product()], shape)
+    }
+
+    pub fn scalar(value: Value) -> Tensor {
+        Tensor::new(vec![value], &[])
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn numel(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.offset == 0
+            && self.storage.len() == self.numel()
+            && self.strides == contiguous_strides(&self.shape)
+    }
+
+    pub fn get(&self, index: &[usize]) -> &Value {
+        assert_eq!(index.len(), self.ndim(), "index {:?} does not match shape {:?}", index, self.shape);
+        let mut pos = self.offset;
+        for ((&i, &dim), &stride) in index.iter().zip(self.shape.iter()).zip(self.strides.iter()) {
+            assert!(i < dim, "index {:?} out of bounds for shape {:?}", index, self.shape);
+            pos += i * stride;
+        }
+        &self.storage[pos]
+    }
+
+    /// Storage positions of every element in row-major order.
+    fn positions(&self) -> Vec<usize> {
+        let mut positions = Vec::with_capacity(self.numel());
+        if self.numel() == 0 {
+            return positions;
+        }
+
+        let mut index = vec![0; self.ndim()];
+        loop {
+            positions.push(self.offset + index.iter().zip(self.strides.iter()).map(|(i, s)| i * s).sum::<usize>());
+
+            let mut axis = self.ndim();
+            loop {
+                if axis == 0 {
+                    return positions;
+                }
+                axis -= 1;
+                index[axis] += 1;
+                if index[axis] < self.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+    }
+
+    /// Elements in row-major order.
+    pub fn values(&self) -> Vec<Value> {
+        self.positions().into_iter().map(|p| self.storage[p].clone()).collect()
+    }
+
+    pub fn data(&self) -> Vec<f64> {
+        self.positions().into_iter().map(|p| self.storage[p].data()).collect()
+    }
+
+    pub fn grad(&self) -> Vec<f64> {
+        self.positions().into_iter().map(|p| self.storage[p].grad()).collect()
+    }
+
+    /// The single element of a tensor with one element.
+    pub fn item(&self) -> Value {
+        assert_eq!(self.numel(), 1, "item() on tensor of shape {:?}", self.shape);
+        self.values().remove(0)
+    }
+
+    pub fn backward(&self) {
+        self.item().backward()
+    }
+
+    pub fn contiguous(&self) -> Tensor {
+        if self.is_contiguous() {
+            return self.clone();
+        }
+        Tensor::new(self.values(), &self.shape)
+    }
+
+    pub fn reshape(&self, shape: &[usize]) -> Tensor {
+        assert_eq!(
+            self.numel(),
+            shape.iter().product::<usize>(),
+            "can not reshape {:?} into {:?}", self.shape, shape
+        );
+
+        let t = self.contiguous();
+        Tensor {
+            shape: shape.to_vec(),
+            strides: contiguous_strides(shape),
+            offset: 0,
+            storage: t.storage,
+        }
+    }
+
+    pub fn permute(&self, axes: &[usize]) -> Tensor {
+        assert_eq!(axes.len(), self.ndim(), "permutation {:?} does not match shape {:?}", axes, self.shape);
+        let mut seen = vec![false; axes.len()];
+        for &axis in axes {
+            assert!(axis < axes.len() && !seen[axis], "invalid permutation {:?}", axes);
+            seen[axis] = true;
+        }
+
+        Tensor {
+            shape: axes.iter().map(|&a| self.shape[a]).collect(),
+            strides: axes.iter().map(|&a| self.strides[a]).collect(),
+            offset: self.offset,
+            storage: self.storage.clone(),
+        }
+    }
+
+    pub fn transpose(&self, a: usize, b: usize) -> Tensor {
+        let mut axes = (0..self.ndim()).collect::<Vec<_>>();
+        axes.swap(a, b);
+        self.permute(&axes)
+    }
+
+    /// Transpose of a 2D tensor.
+    pub fn t(&self) -> Tensor {
+        assert_eq!(self.ndim(), 2, "t() expects a 2D tensor, got {:?}", self.shape);
+        self.transpose(0, 1)
+    }
+
+    /// Elements `start..end` along `axis`.
+    pub fn slice(&self, axis: usize, start: usize, end: usize) -> Tensor {
+        assert!(axis < self.ndim(), "axis {} out of range for shape {:?}", axis, self.shape);
+        assert!(start <= end && end <= self.shape[axis], "slice {}..{} out of range for shape {:?}", start, end, self.shape);
+
+        let mut shape = self.shape.clone();
+        shape[axis] = end - start;
+
+        Tensor {
+            shape,
+            strides: self.strides.clone(),
+            offset: self.offset + start * self.strides[axis],
+            storage: self.storage.clone(),
+        }
+    }
+
+    pub fn broadcast_to(&self, shape: &[usize]) -> Tensor {
+        assert!(shape.len() >= self.ndim(), "can not broadcast {:?} to {:?}", self.shape, shape);
+        let lead = shape.len() - self.ndim();
+        let mut strides = vec![0; shape.len()];
+        for i in 0..self.ndim() {
+            if self.shape[i] == shape[lead + i] {
+                strides[lead + i] = self.strides[i];
+            } else {
+                assert_eq!(self.shape[i], 1, "can not broadcast {:?} to {:?}", self.shape, shape);
+            }
+        }
+
+        Tensor {
+            shape: shape.to_vec(),
+            strides,
+            offset: self.offset,
+            storage: self.storage.clone(),
+        }
+    }
+
+    pub fn map<F>(&self, f: F) -> Tensor
+    where
+        F: Fn(&Value) -> Value,
+    {
+        Tensor::new(self.values().iter().map(f).collect(), &self.shape)
+    }
+
+    pub fn zip_with<F>(&self, other: &Tensor, f: F) -> Tensor
+    where
+        F: Fn(&Value, &Value) -> Value,
+    {
+        let shape = broadcast_shape(&self.shape, &other.shape);
+        let a = self.broadcast_to(&shape).values();
+        let b = other.broadcast_to(&shape).values();
+        Tensor::new(a.iter().zip(b.iter()).map(|(a, b)| f(a, b)).collect(), &shape)
+    }
+
+    pub fn add(&self, other: &Tensor) -> Tensor {
+        self.zip_with(other, |a, b| a.add(b))
+    }
+
+    pub fn sub(&self, other: &Tensor) -> Tensor {
+        self.zip_with(other, |a, b| a.sub(b))
+    }
+
+    pub fn mul(&self, other: &Tensor) -> Tensor {
+        self.zip_with(other, |a, b| a.mul(b))
+    }
+
+    pub fn div(&self, other: &Tensor) -> Tensor {
+        self.zip_with(other, |a, b| a.div(b))
+    }
+
+    pub fn relu(&self) -> Tensor {
+        self.map(|v| v.relu())
+    }
+
+    pub fn exp(&self) -> Tensor {
+        self.map(|v| v.exp())
+    }
+
+    pub fn log(&self) -> Tensor {
+        self.map(|v| v.log())
+    }
+
+    /// Matrix product of two 2D tensors.
+    pub fn matmul(&self, other: &Tensor) -> Tensor {
+        assert!(
+            self.ndim() == 2 && other.ndim() == 2 && self.shape[1] == other.shape[0],
+            "can not matmul {:?} with {:?}", self.shape, other.shape
+        );
+
+        let (m, k, n) = (self.shape[0], self.shape[1], other.shape[1]);
+        let mut values = Vec::with_capacity(m * n);
+        for i in 0..m {
+            for j in 0..n {
+                values.push((0..k).map(|l| self.get(&[i, l]).mul(other.get(&[l, j]))).sum::<Value>());
+            }
+        }
+
+        Tensor::new(values, &[m, n])
+    }
+
+    /// Reduces `axis` by applying `f` to each lane along it. The axis is
+    /// removed from the shape.
+    fn reduce<F>(&self, axis: usize, f: F) -> Tensor
+    where
+        F: Fn(&[Value]) -> Value,
+    {
+        assert!(axis < self.ndim(), "axis {} out of range for shape {:?}", axis, self.shape);
+
+        let mut axes = (0..self.ndim()).filter(|&a| a != axis).collect::<Vec<_>>();
+        let shape = axes.iter().map(|&a| self.shape[a]).collect::<Vec<_>>();
+        axes.push(axis);
+
+        // An empty axis still gives one (empty) lane per output element.
+        let values = if self.shape[axis] == 0 {
+            (0..shape.iter().product()).map(|_| f(&[])).collect()
+        } else {
+            self.permute(&axes).values().chunks(self.shape[axis]).map(f).collect()
+        };
+        Tensor::new(values, &shape)
+    }
+
+    /// Replaces each lane along `axis` by `f` of it, keeping the shape.
+    fn map_lanes<F>(&self, axis: usize, f: F) -> Tensor
+    where
+        F: Fn(&[Value]) -> Vec<Value>,
+    {
+        assert!(axis < self.ndim(), "axis {} out of range for shape {:?}", axis, self.shape);
+
+        let mut axes = (0..self.ndim()).filter(|&a| a != axis).collect::<Vec<_>>();
+        axes.push(axis);
+        let permuted = self.permute(&axes);
+
+        let values = permuted.values()
+            .chunks(self.shape[axis].max(1))
+            .flat_map(f)
+            .collect();
+
+        let mut inverse = vec![0; axes.len()];
+        for (i, &a) in axes.iter().enumerate() {
+            inverse[a] = i;
+        }
+        Tensor::new(values, permuted.shape()).permute(&inverse)
+    }
+
+    /// Softmax along `axis`, see `softmax`.
+    pub fn softmax(&self, axis: usize) -> Tensor {
+        self.softmax_with_temperature(axis, 1.0)
+    }
+
+    pub fn softmax_with_temperature(&self, axis: usize, temperature: f64) -> Tensor {
+        self.map_lanes(axis, |lane| crate::softmax_with_temperature(lane, temperature))
+    }
+
+    /// Sums over an empty axis are 0.
+    pub fn sum(&self, axis: usize) -> Tensor {
+        self.reduce(axis, |lane| lane.iter().sum::<Value>())
+    }
+
+    /// Means over an empty axis are NaN, as in NumPy.
+    pub fn mean(&self, axis: usize) -> Tensor {
+        assert!(axis < self.ndim(), "axis {} out of range for shape {:?}", axis, self.shape);
+        let n = self.shape[axis] as f64;
+        self.reduce(axis, |lane| lane.iter().sum::<Value>() / n)
+    }
+
+    pub fn max(&self, axis: usize) -> Tensor {
+        self.reduce(axis, |lane| {
+            assert!(!lane.is_empty(), "max over empty axis {} of shape {:?}", axis, self.shape);
+            lane.iter().skip(1).fold(lane[0].clone(), |acc, v| acc.max(v))
+        })
+    }
+
+    pub fn sum_all(&self) -> Value {
+        self.values().iter().sum::<Value>()
+    }
+
+    pub fn mean_all(&self) -> Value {
+        self.sum_all() / self.numel() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck;
+
+    #[test]
+    fn test_views() {
+        let t = Tensor::from_f64(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        assert_eq!(t.t().shape(), &[3, 2]);
+        assert_eq!(t.t().data(), vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert_eq!(t.reshape(&[3, 2]).data(), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(t.t().reshape(&[6]).data(), vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert_eq!(t.slice(1, 1, 3).data(), vec![2.0, 3.0, 5.0, 6.0]);
+        assert_eq!(t.slice(0, 1, 2).get(&[0, 2]).data(), 6.0);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let a = Tensor::from_f64(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let row = Tensor::from_f64(&[10.0, 20.0, 30.0], &[3]);
+        let col = Tensor::from_f64(&[100.0, 200.0], &[2, 1]);
+
+        assert_eq!(a.add(&row).data(), vec![11.0, 22.0, 33.0, 14.0, 25.0, 36.0]);
+        assert_eq!(a.mul(&col).data(), vec![100.0, 200.0, 300.0, 800.0, 1000.0, 1200.0]);
+        assert_eq!(row.add(&col).shape(), &[2, 3]);
+    }
+
+    #[test]
+    fn test_reductions() {
+        let t = Tensor::from_f64(&[1.0, 5.0, 3.0, 4.0, 2.0, 6.0], &[2, 3]);
+        assert_eq!(t.sum(0).data(), vec![5.0, 7.0, 9.0]);
+        assert_eq!(t.sum(1).data(), vec![9.0, 12.0]);
+        assert_eq!(t.mean(1).data(), vec![3.0, 4.0]);
+        assert_eq!(t.max(0).data(), vec![4.0, 5.0, 6.0]);
+        assert_eq!(t.sum(0).sum(0).shape(), &[] as &[usize]);
+        assert_eq!(t.sum_all().data(), 21.0);
+    }
+
+    #[test]
+    fn test_empty_axis_reductions() {
+        let t = Tensor::from_f64(&[], &[2, 0]);
+        assert_eq!(t.sum(1).shape(), &[2]);
+        assert_eq!(t.sum(1).data(), vec![0.0, 0.0]);
+        assert!(t.mean(1).data().iter().all(|x| x.is_nan()));
+        assert_eq!(t.sum(0).shape(), &[0]);
+        assert_eq!(t.max(0).shape(), &[0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "max over empty axis 1")]
+    fn test_max_empty_axis() {
+        Tensor::from_f64(&[], &[2, 0]).max(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "axis 2 out of range")]
+    fn test_mean_axis_out_of_range() {
+        Tensor::from_f64(&[1.0, 2.0], &[1, 2]).mean(2);
+    }
+
+    #[test]
+    fn test_softmax() {
+        let t = Tensor::from_f64(&[1.0, 2.0, 3.0, -1000.0, -1000.0, 1000.0], &[2, 3]);
+
+        let rows = t.softmax(1);
+        assert_eq!(rows.shape(), &[2, 3]);
+        for row in 0..2 {
+            assert!(((0..3).map(|j| rows.get(&[row, j]).data()).sum::<f64>() - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(rows.get(&[1, 2]).data(), 1.0);
+        assert_eq!(rows.get(&[1, 0]).data(), 0.0);
+
+        let cols = t.softmax(0);
+        for col in 0..3 {
+            assert!((cols.get(&[0, col]).data() + cols.get(&[1, col]).data() - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(cols.get(&[0, 0]).data(), 1.0);
+
+        let expected = crate::softmax(&[Value::new(1.0), Value::new(2.0), Value::new(3.0)]);
+        for (j, e) in expected.iter().enumerate() {
+            assert_eq!(rows.get(&[0, j]).data(), e.data());
+        }
+    }
+
+    #[test]
+    fn test_matmul() {
+        let a = Tensor::from_f64(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let b = Tensor::from_f64(&[7.0, 8.0, 9.0, 10.0, 11.0, 12.0], &[3, 2]);
+        let c = a.matmul(&b);
+        assert_eq!(c.shape(), &[2, 2]);
+        assert_eq!(c.data(), vec![58.0, 64.0, 139.0, 154.0]);
+        assert_eq!(a.matmul(&a.t()).data(), vec![14.0, 32.0, 32.0, 77.0]);
+    }
+
+    #[test]
+    fn test_grads() {
+        let inputs = [0.5, -1.0, 2.0, 0.3, 1.5, -0.7, 0.2, 0.9];
+        gradcheck(&inputs, |x| {
+            let a = Tensor::new(x[..6].to_vec(), &[2, 3]);
+            let b = Tensor::new(x[6..].to_vec(), &[2, 1]);
+            a.t().matmul(&a.mul(&b)).relu().sum(1).mean(0).item()
+        }).assert_ok();
+
+        gradcheck(&inputs, |x| {
+            let a = Tensor::new(x.to_vec(), &[2, 4]);
+            a.sub(&a.max(1).reshape(&[2, 1])).exp().sum_all()
+        }).assert_ok();
+    }
+}
@@ -1,15 +1,50 @@
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::hash::Hasher;
+use std::collections::VecDeque;
+use std::fmt::Write;
 use std::iter::Sum;
-use std::ops::Div;
-use std::ops::Sub;
-use std::hash::Hash;
 use std::rc::Rc;
 use std::sync::atomic::AtomicUsize;
 
 static ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+thread_local! {
+    static DETECT_ANOMALY: Cell<bool> = const { Cell::new(false) };
+}
+
+// How many ops back the anomaly report follows the operands.
+const TRACE_DEPTH: usize = 6;
+const TRACE_NODES: usize = 24;
+
+/// Turns anomaly detection on or off for this thread. While on, every op
+/// checks its output and every gradient contribution in `backward` for NaN or
+/// infinity and panics with the op, its operands and the ops that created
+/// them. Off by default since it costs a check per op.
+pub fn set_detect_anomaly(enabled: bool) {
+    DETECT_ANOMALY.with(|d| d.set(enabled));
+}
+
+pub fn is_detect_anomaly() -> bool {
+    DETECT_ANOMALY.with(|d| d.get())
+}
+
+/// Runs `f` with anomaly detection on and restores the previous setting
+/// afterwards, also when `f` panics.
+pub fn detect_anomaly<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_detect_anomaly(self.0);
+        }
+    }
+
+    let _restore = Restore(is_detect_anomaly());
+    set_detect_anomaly(true);
+    f()
+}
+
 #[derive(Debug)]
 enum BinOPType {
     Mul,
@@ -23,7 +58,31 @@ enum BinOPType {
 #[derive(Debug)]
 enum UnaryOPType {
     Log,
-    Exp
+    Log2,
+    Log10,
+    Log1p,
+    Exp,
+    Powf(f64),
+    Powi(i32),
+    Sqrt,
+    Tanh,
+    Sigmoid,
+    Abs,
+    Sin,
+    Cos,
+    Neg,
+    Square,
+    Reciprocal,
+    Softplus,
+    Clamp(f64, f64),
+}
+
+/// Ops over many inputs whose local gradients are computed together in the
+/// forward pass.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FusedOPType {
+    LogSoftmax,
+    CrossEntropy,
 }
 
 #[derive(Debug)]
@@ -38,6 +97,98 @@ enum Parent {
         op: UnaryOPType,
         inner: Rc<RefCell<Inner>>,
     },
+    Fused {
+        op: FusedOPType,
+        inputs: Vec<Rc<RefCell<Inner>>>,
+        /// Derivative of the output with respect to each input.
+        local_grads: Vec<f64>,
+    },
+}
+
+impl BinOPType {
+    fn name(&self) -> &'static str {
+        match self {
+            BinOPType::Mul => "Mul",
+            BinOPType::Div => "Div",
+            BinOPType::Add => "Add",
+            BinOPType::Sub => "Sub",
+            BinOPType::Max => "Max",
+            BinOPType::Min => "Min",
+        }
+    }
+}
+
+impl UnaryOPType {
+    fn name(&self) -> &'static str {
+        match self {
+            UnaryOPType::Log => "Log",
+            UnaryOPType::Log2 => "Log2",
+            UnaryOPType::Log10 => "Log10",
+            UnaryOPType::Log1p => "Log1p",
+            UnaryOPType::Exp => "Exp",
+            UnaryOPType::Powf(_) => "Powf",
+            UnaryOPType::Powi(_) => "Powi",
+            UnaryOPType::Sqrt => "Sqrt",
+            UnaryOPType::Tanh => "Tanh",
+            UnaryOPType::Sigmoid => "Sigmoid",
+            UnaryOPType::Abs => "Abs",
+            UnaryOPType::Sin => "Sin",
+            UnaryOPType::Cos => "Cos",
+            UnaryOPType::Neg => "Neg",
+            UnaryOPType::Square => "Square",
+            UnaryOPType::Reciprocal => "Reciprocal",
+            UnaryOPType::Softplus => "Softplus",
+            UnaryOPType::Clamp(_, _) => "Clamp",
+        }
+    }
+}
+
+impl Parent {
+    fn inners(&self) -> Vec<&Rc<RefCell<Inner>>> {
+        match self {
+            Parent::None => vec![],
+            Parent::BinOp { left, right, .. } => vec![left, right],
+            Parent::UnaryOp { inner, .. } => vec![inner],
+            Parent::Fused { inputs, .. } => inputs.iter().collect(),
+        }
+    }
+
+    fn op_name(&self) -> Option<&'static str> {
+        match self {
+            Parent::None => None,
+            Parent::BinOp { op, .. } => Some(op.name()),
+            Parent::UnaryOp { op, .. } => Some(op.name()),
+            Parent::Fused { op: FusedOPType::LogSoftmax, .. } => Some("LogSoftmax"),
+            Parent::Fused { op: FusedOPType::CrossEntropy, .. } => Some("CrossEntropy"),
+        }
+    }
+
+    // The op with its arguments, e.g. `Powi(3)`.
+    fn op_label(&self) -> Option<String> {
+        match self {
+            Parent::None => None,
+            Parent::BinOp { op, .. } => Some(format!("{:?}", op)),
+            Parent::UnaryOp { op, .. } => Some(format!("{:?}", op)),
+            Parent::Fused { op, .. } => Some(format!("{:?}", op)),
+        }
+    }
+}
+
+/// Approximate heap size of one graph node: the `Rc` allocation holding the
+/// counters and the `RefCell<Inner>`.
+pub(crate) const NODE_BYTES: usize = 2 * std::mem::size_of::<usize>() + std::mem::size_of::<RefCell<Inner>>();
+
+/// A node of the graph behind a `Value`, for tools that inspect the graph.
+#[derive(Debug, Clone)]
+pub(crate) struct GraphNode {
+    pub id: usize,
+    pub data: f64,
+    pub grad: f64,
+    /// Variant name of the op, `None` for leaves.
+    pub op: Option<&'static str>,
+    pub op_label: Option<String>,
+    /// Ids of the operands, twice for `x * x`.
+    pub parents: Vec<usize>,
 }
 
 #[derive(Debug)]
@@ -48,13 +199,46 @@ struct Inner {
     parent: Parent,
 }
 
+fn write_trace(node: &Inner, depth: usize, budget: &mut usize, out: &mut String) {
+    if *budget == 0 {
+        return;
+    }
+    *budget -= 1;
+
+    let op = node.parent.op_label().unwrap_or_else(|| "leaf".to_string());
+    write!(out, "\n{}#{} {} = {}", "  ".repeat(depth), node.id, op, node.data).unwrap();
+    if depth < TRACE_DEPTH {
+        for parent in node.parent.inners() {
+            write_trace(&parent.borrow(), depth + 1, budget, out);
+        }
+    }
+}
+
+fn report_anomaly(phase: &str, node: &Inner, what: &str, value: f64) -> ! {
+    let op = node.parent.op_label().unwrap_or_else(|| "leaf".to_string());
+    let operands = node.parent.inners().iter().map(|p| p.borrow().data).collect::<Vec<_>>();
+
+    let mut msg = format!(
+        "anomaly detected in {}: {} {} is {} for operands {:?}",
+        phase, op, what, value, operands
+    );
+    if phase == "backward" {
+        write!(msg, " and output grad {}", node.grad).unwrap();
+    }
+    msg.push_str("\ncreated by:");
+    let mut budget = TRACE_NODES;
+    write_trace(node, 1, &mut budget, &mut msg);
+
+    panic!("{}", msg);
+}
+
 impl Inner {
     fn new(data: f64, parent: Parent) -> Inner {
         Inner {
             id: ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
             data,
             grad: 0.0,
-            parent: parent,
+            parent,
         }
     }
 
@@ -63,76 +247,128 @@ impl Inner {
             Parent::None => {
                 log::debug!("no parent")
             },
-            Parent::BinOp { op, left, right } => {
-                let mut left = left.borrow_mut();
-                let mut right = right.borrow_mut();
+            Parent::BinOp { op, left: left_ref, right: right_ref } => {
+                // left and right may be the same node (x * x), so read both
+                // operands first and only then add the contributions.
+                let left = left_ref.borrow().data;
+                let right = right_ref.borrow().data;
 
-                match op {
+                let (left_grad, right_grad) = match op {
                     BinOPType::Mul => {
                         log::debug!("mul");
-                        left.grad += right.data * self.grad;
-                        right.grad += left.data * self.grad;
+                        (right * self.grad, left * self.grad)
                     },
                     BinOPType::Add => {
                         log::debug!("add");
-                        left.grad += self.grad;
-                        right.grad += self.grad;
+                        (self.grad, self.grad)
                     },
                     BinOPType::Sub => {
                         log::debug!("sub");
-                        left.grad += self.grad;
-                        right.grad += -self.grad;
+                        (self.grad, -self.grad)
                     },
                     BinOPType::Max => {
                         log::debug!("max");
-                        left.grad += if left.data > right.data {
-                            self.grad
-                        } else {
-                            0.0
-                        };
-                        right.grad += if right.data > left.data {
-                            self.grad
-                        } else {
-                            0.0
-                        };
+                        (
+                            if left > right { self.grad } else { 0.0 },
+                            if right > left { self.grad } else { 0.0 },
+                        )
                     },
                     BinOPType::Min => {
                         log::debug!("min");
-                        left.grad += if left.data < right.data {
-                            self.grad
-                        } else {
-                            0.0
-                        };
-                        right.grad += if right.data < left.data {
-                            self.grad
-                        } else {
-                            0.0
-                        };
+                        (
+                            if left < right { self.grad } else { 0.0 },
+                            if right < left { self.grad } else { 0.0 },
+                        )
                     },
                     BinOPType::Div => {
                         log::debug!("div");
-                        left.grad += self.grad / right.data;
-                        right.grad += -(left.data / right.data.powi(2)) * self.grad;
+                        (self.grad / right, -(left / right.powi(2)) * self.grad)
                     },
+                };
+
+                if is_detect_anomaly() {
+                    if !left_grad.is_finite() {
+                        report_anomaly("backward", self, "left grad", left_grad);
+                    }
+                    if !right_grad.is_finite() {
+                        report_anomaly("backward", self, "right grad", right_grad);
+                    }
                 }
+
+                left_ref.borrow_mut().grad += left_grad;
+                right_ref.borrow_mut().grad += right_grad;
             },
             Parent::UnaryOp { op, inner } => {
                 log::debug!("unaryop");
-                let mut inner = inner.borrow_mut();
+                let x = inner.borrow().data;
 
-                match op {
-                    UnaryOPType::Log => {
-                        inner.grad += self.grad / inner.data;
+                let local = match op {
+                    UnaryOPType::Log => 1.0 / x,
+                    UnaryOPType::Log2 => 1.0 / (x * std::f64::consts::LN_2),
+                    UnaryOPType::Log10 => 1.0 / (x * std::f64::consts::LN_10),
+                    UnaryOPType::Log1p => 1.0 / (1.0 + x),
+                    UnaryOPType::Exp => self.data,
+                    // x^0 is constant, and 0 * 0^-1 would be NaN at x = 0.
+                    UnaryOPType::Powf(p) if *p == 0.0 => 0.0,
+                    UnaryOPType::Powf(p) => p * x.powf(p - 1.0),
+                    UnaryOPType::Powi(0) => 0.0,
+                    UnaryOPType::Powi(n) => *n as f64 * x.powi(n - 1),
+                    UnaryOPType::Sqrt => 0.5 / self.data,
+                    UnaryOPType::Tanh => 1.0 - self.data * self.data,
+                    UnaryOPType::Sigmoid => self.data * (1.0 - self.data),
+                    UnaryOPType::Abs => {
+                        if x > 0.0 {
+                            1.0
+                        } else if x < 0.0 {
+                            -1.0
+                        } else {
+                            0.0
+                        }
+                    },
+                    UnaryOPType::Sin => x.cos(),
+                    UnaryOPType::Cos => -x.sin(),
+                    UnaryOPType::Neg => -1.0,
+                    UnaryOPType::Square => 2.0 * x,
+                    UnaryOPType::Reciprocal => -1.0 / (x * x),
+                    UnaryOPType::Softplus => sigmoid(x),
+                    UnaryOPType::Clamp(min, max) => {
+                        if x >= *min && x <= *max {
+                            1.0
+                        } else {
+                            0.0
+                        }
                     },
-                    UnaryOPType::Exp => {
-                        inner.grad += self.grad * inner.data.exp();
+                };
+
+                let grad = self.grad * local;
+                if is_detect_anomaly() && !grad.is_finite() {
+                    report_anomaly("backward", self, "grad", grad);
+                }
+                inner.borrow_mut().grad += grad;
+            },
+            Parent::Fused { inputs, local_grads, .. } => {
+                log::debug!("fused");
+                for (input, local) in inputs.iter().zip(local_grads.iter()) {
+                    let grad = self.grad * local;
+                    if is_detect_anomaly() && !grad.is_finite() {
+                        report_anomaly("backward", self, "grad", grad);
                     }
+                    input.borrow_mut().grad += grad;
                 }
             },
         }
     }
 }
 
+pub(crate) fn sigmoid(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Value {
     inner: Rc<RefCell<Inner>>,
@@ -153,135 +389,178 @@ impl Value {
         self.inner.borrow().grad
     }
 
-    pub fn sub_assign(&self, v: f64) {
-        //println!("update value: {}", v);
+    pub(crate) fn add_grad(&self, grad: f64) {
+        self.inner.borrow_mut().grad += grad;
+    }
+
+    /// Subtracts `v` from the data in place, keeping the node. Unlike `-=`,
+    /// which rebinds to a new node, this is what parameter updates need.
+    pub fn decrease_data(&self, v: f64) {
         self.inner.borrow_mut().data -= v
     }
 
-    pub fn mul(&self, other: &Value) -> Value {
-        let new_data = self.inner.borrow().data * other.inner.borrow().data;
+    #[deprecated(note = "use decrease_data, `-=` now builds a new node instead")]
+    pub fn sub_assign(&self, v: f64) {
+        self.decrease_data(v)
+    }
 
+    /// Overwrites the data in place, e.g. when loading saved weights.
+    pub fn set_data(&self, data: f64) {
+        self.inner.borrow_mut().data = data;
+    }
+
+    fn binary(&self, other: &Value, op: BinOPType, new_data: f64) -> Value {
         Value { 
             inner: Rc::new(RefCell::new(Inner::new(
                 new_data, 
                 Parent::BinOp {
-                    op: BinOPType::Mul,
+                    op,
                     left: self.inner.clone(),
                     right: other.inner.clone(),
                 }
             ))), 
+        }.checked()
+    }
+
+    fn checked(self) -> Value {
+        if is_detect_anomaly() && !self.data().is_finite() {
+            report_anomaly("forward", &self.inner.borrow(), "output", self.data());
         }
+        self
     }
 
-    pub fn div(&self, other: &Value) -> Value {
-        let new_data = self.inner.borrow().data / other.inner.borrow().data;
+    pub fn mul(&self, other: &Value) -> Value {
+        self.binary(other, BinOPType::Mul, self.data() * other.data())
+    }
 
-        Value { 
-            inner: Rc::new(RefCell::new(Inner::new(
-                new_data, 
-                Parent::BinOp {
-                    op: BinOPType::Div,
-                    left: self.inner.clone(),
-                    right: other.inner.clone(),
-                }
-            ))), 
-        }
+    pub fn div(&self, other: &Value) -> Value {
+        self.binary(other, BinOPType::Div, self.data() / other.data())
     }
 
     pub fn add(&self, other: &Value) -> Value {
-        let new_data = self.inner.borrow().data + other.inner.borrow().data;
-
-        Value { 
-            inner: Rc::new(RefCell::new(Inner::new(
-                new_data, 
-                Parent::BinOp {
-                    op: BinOPType::Add,
-                    left: self.inner.clone(),
-                    right: other.inner.clone(),
-                }
-            ))), 
-        }
+        self.binary(other, BinOPType::Add, self.data() + other.data())
     }
 
     pub fn sub(&self, other: &Value) -> Value {
-        let new_data = self.inner.borrow().data - other.inner.borrow().data;
+        self.binary(other, BinOPType::Sub, self.data() - other.data())
+    }
 
-        Value { 
-            inner: Rc::new(RefCell::new(Inner::new(
-                new_data, 
-                Parent::BinOp {
-                    op: BinOPType::Sub,
-                    left: self.inner.clone(),
-                    right: other.inner.clone(),
-                }
-            ))), 
-        }
+    pub fn max(&self, other: &Value) -> Value {
+        self.binary(other, BinOPType::Max, self.data().max(other.data()))
     }
 
-    // pub fn pow(&self, other: f64) -> Value {
-        
-    // }
+    pub fn min(&self, other: &Value) -> Value {
+        self.binary(other, BinOPType::Min, self.data().min(other.data()))
+    }
 
     pub fn relu(&self) -> Value {
         self.max(&Value::new(0.0))
     }
 
-    pub fn log(&self) -> Value {
-        let new_data = self.inner.borrow().data.log2();
-
+    fn unary(&self, op: UnaryOPType, new_data: f64) -> Value {
         Value { 
             inner: Rc::new(RefCell::new(Inner::new(
                 new_data, 
                 Parent::UnaryOp {
-                    op: UnaryOPType::Log,
+                    op,
                     inner: self.inner.clone(),
                 }
             ))), 
-        }
+        }.checked()
     }
 
-    pub fn exp(&self) -> Value {
-        let new_data = self.inner.borrow().data.exp();
-
-        Value { 
+    /// A node over all of `inputs` whose gradient with respect to input `i`
+    /// is `local_grads[i]`, for ops with a cheaper or more stable combined
+    /// backward than their elementwise graph.
+    pub(crate) fn fused(inputs: &[Value], op: FusedOPType, new_data: f64, local_grads: Vec<f64>) -> Value {
+        assert_eq!(inputs.len(), local_grads.len(), "fused op needs one local gradient per input");
+        Value {
             inner: Rc::new(RefCell::new(Inner::new(
-                new_data, 
-                Parent::UnaryOp {
-                    op: UnaryOPType::Exp,
-                    inner: self.inner.clone(),
+                new_data,
+                Parent::Fused {
+                    op,
+                    inputs: inputs.iter().map(|v| v.inner.clone()).collect(),
+                    local_grads,
                 }
-            ))), 
-        }
+            ))),
+        }.checked()
     }
 
-    pub fn max(&self, other: &Value) -> Value {
-        let new_data = self.inner.borrow().data.max(other.inner.borrow().data);
+    /// Natural logarithm.
+    pub fn log(&self) -> Value {
+        self.unary(UnaryOPType::Log, self.data().ln())
+    }
 
-        Value { 
-            inner: Rc::new(RefCell::new(Inner::new(
-                new_data, 
-                Parent::BinOp {
-                    op: BinOPType::Max,
-                    left: self.inner.clone(),
-                    right: other.inner.clone(),
-                }
-            ))), 
-        }
+    pub fn log2(&self) -> Value {
+        self.unary(UnaryOPType::Log2, self.data().log2())
     }
 
-    pub fn min(&self, other: &Value) -> Value {
-        let new_data = self.inner.borrow().data.min(other.inner.borrow().data);
+    pub fn log10(&self) -> Value {
+        self.unary(UnaryOPType::Log10, self.data().log10())
+    }
 
-        Value { 
-            inner: Rc::new(RefCell::new(Inner::new(
-                new_data, 
-                Parent::BinOp {
-                    op: BinOPType::Min,
-                    left: self.inner.clone(),
-                    right: other.inner.clone(),
-                }
-            ))), 
-        }
+    /// `ln(1 + x)`, accurate for small `x`.
+    pub fn log1p(&self) -> Value {
+        self.unary(UnaryOPType::Log1p, self.data().ln_1p())
+    }
+
+    pub fn exp(&self) -> Value {
+        self.unary(UnaryOPType::Exp, self.data().exp())
+    }
+
+    pub fn powf(&self, exponent: f64) -> Value {
+        self.unary(UnaryOPType::Powf(exponent), self.data().powf(exponent))
+    }
+
+    pub fn powi(&self, exponent: i32) -> Value {
+        self.unary(UnaryOPType::Powi(exponent), self.data().powi(exponent))
+    }
+
+    pub fn sqrt(&self) -> Value {
+        self.unary(UnaryOPType::Sqrt, self.data().sqrt())
+    }
+
+    pub fn tanh(&self) -> Value {
+        self.unary(UnaryOPType::Tanh, self.data().tanh())
+    }
+
+    pub fn sigmoid(&self) -> Value {
+        self.unary(UnaryOPType::Sigmoid, sigmoid(self.data()))
+    }
+
+    pub fn abs(&self) -> Value {
+        self.unary(UnaryOPType::Abs, self.data().abs())
+    }
+
+    pub fn sin(&self) -> Value {
+        self.unary(UnaryOPType::Sin, self.data().sin())
+    }
+
+    pub fn cos(&self) -> Value {
+        self.unary(UnaryOPType::Cos, self.data().cos())
+    }
+
+    pub fn neg(&self) -> Value {
+        self.unary(UnaryOPType::Neg, -self.data())
+    }
+
+    pub fn square(&self) -> Value {
+        self.unary(UnaryOPType::Square, self.data() * self.data())
+    }
+
+    pub fn reciprocal(&self) -> Value {
+        self.unary(UnaryOPType::Reciprocal, 1.0 / self.data())
+    }
+
+    /// `ln(1 + e^x)`, computed without overflowing for large `x`.
+    pub fn softplus(&self) -> Value {
+        let x = self.data();
+        self.unary(UnaryOPType::Softplus, x.max(0.0) + (-x.abs()).exp().ln_1p())
+    }
+
+    /// Gradient flows only where `min <= x <= max`.
+    pub fn clamp(&self, min: f64, max: f64) -> Value {
+        self.unary(UnaryOPType::Clamp(min, max), self.data().clamp(min, max))
     }
 
     pub fn zero_grad(&self) {
@@ -290,41 +569,72 @@ impl Value {
 
     pub fn backward(&self) {
         self.inner.borrow_mut().grad = 1.0;
+
+        // Every node has to receive all of its gradient before it passes it on
+        // to its parents, so walk the graph in post-order and run backward in
+        // reverse. A node reachable through several paths is only emitted once
+        // all of its parents have been emitted.
         let mut toppo = vec![];
-        let mut stack = vec![self.inner.clone()];
+        let mut stack = vec![(self.inner.clone(), false)];
         let mut visited = HashSet::new();
-        loop {
-            let inner = match stack.pop() {
-                Some(inner) => inner,
-                None => break,
-            };
-            {
-                let inner: std::cell::Ref<'_, Inner> = inner.borrow();
-
-                if visited.contains(&inner.id) {
-                    continue;
-                }
+        while let Some((inner, expanded)) = stack.pop() {
+            if expanded {
+                toppo.push(inner);
+                continue;
+            }
 
-                visited.insert(inner.id);
+            let id = inner.borrow().id;
+            if !visited.insert(id) {
+                continue;
+            }
 
-                match &inner.parent {
-                    Parent::None => {},
-                    Parent::BinOp { left, right, .. } => {
-                        stack.push(left.clone());
-                        stack.push(right.clone());
-                    },
-                    Parent::UnaryOp { inner, .. } => {
-                        stack.push(inner.clone());
+            stack.push((inner.clone(), true));
+            for parent in inner.borrow().parent.inners() {
+                stack.push((parent.clone(), false));
+            }
+        }
+
+        for inner in toppo.iter().rev() {
+            inner.borrow().backward();
+        }
+    }
+}
+
+impl Value {
+    /// Breadth first walk from this value to its leaves, each node once.
+    /// Nodes deeper than `max_depth` are skipped and the walk stops after
+    /// `max_nodes` nodes.
+    pub(crate) fn graph_nodes(&self, max_depth: Option<usize>, max_nodes: Option<usize>) -> Vec<GraphNode> {
+        let mut nodes = vec![];
+        let mut queue = VecDeque::from([(self.inner.clone(), 0)]);
+        let mut visited = HashSet::from([self.inner.borrow().id]);
+
+        while let Some((inner, depth)) = queue.pop_front() {
+            if max_nodes.is_some_and(|max| nodes.len() >= max) {
+                break;
+            }
+
+            let inner = inner.borrow();
+            let parents = inner.parent.inners();
+            if max_depth.map_or(true, |max| depth < max) {
+                for parent in parents.iter() {
+                    if visited.insert(parent.borrow().id) {
+                        queue.push_back(((*parent).clone(), depth + 1));
                     }
                 }
-            } 
+            }
 
-            toppo.push(inner.clone());
+            nodes.push(GraphNode {
+                id: inner.id,
+                data: inner.data,
+                grad: inner.grad,
+                op: inner.parent.op_name(),
+                op_label: inner.parent.op_label(),
+                parents: parents.iter().map(|p| p.borrow().id).collect(),
+            });
         }
 
-        for inner in toppo {
-            inner.borrow().backward();
-        }
+        nodes
     }
 }
 
@@ -346,19 +656,395 @@ impl<'a> Sum<&'a Value> for Value {
     }
 }
 
+// The traits are implemented with full paths instead of being imported so that
+// `a.add(&b)` keeps resolving to the borrowing inherent methods in this module.
+macro_rules! impl_bin_op {
+    ($trait:ident, $method:ident, $assign_trait:ident, $assign_method:ident) => {
+        impl std::ops::$trait<&Value> for &Value {
+            type Output = Value;
+
+            fn $method(self, rhs: &Value) -> Value {
+                Value::$method(self, rhs)
+            }
+        }
+
+        impl std::ops::$trait<Value> for &Value {
+            type Output = Value;
+
+            fn $method(self, rhs: Value) -> Value {
+                Value::$method(self, &rhs)
+            }
+        }
+
+        impl std::ops::$trait<&Value> for Value {
+            type Output = Value;
+
+            fn $method(self, rhs: &Value) -> Value {
+                Value::$method(&self, rhs)
+            }
+        }
+
+        impl std::ops::$trait<Value> for Value {
+            type Output = Value;
+
+            fn $method(self, rhs: Value) -> Value {
+                Value::$method(&self, &rhs)
+            }
+        }
+
+        impl std::ops::$trait<f64> for &Value {
+            type Output = Value;
+
+            fn $method(self, rhs: f64) -> Value {
+                Value::$method(self, &Value::new(rhs))
+            }
+        }
+
+        impl std::ops::$trait<f64> for Value {
+            type Output = Value;
+
+            fn $method(self, rhs: f64) -> Value {
+                Value::$method(&self, &Value::new(rhs))
+            }
+        }
+
+        impl std::ops::$trait<&Value> for f64 {
+            type Output = Value;
+
+            fn $method(self, rhs: &Value) -> Value {
+                Value::$method(&Value::new(self), rhs)
+            }
+        }
+
+        impl std::ops::$trait<Value> for f64 {
+            type Output = Value;
+
+            fn $method(self, rhs: Value) -> Value {
+                Value::$method(&Value::new(self), &rhs)
+            }
+        }
+
+        // The assign variants rebind the value to a new node in the graph, use
+        // `decrease_data` or `set_data` to change a parameter in place.
+        impl std::ops::$assign_trait<&Value> for Value {
+            fn $assign_method(&mut self, rhs: &Value) {
+                *self = Value::$method(self, rhs);
+            }
+        }
+
+        impl std::ops::$assign_trait<Value> for Value {
+            fn $assign_method(&mut self, rhs: Value) {
+                *self = Value::$method(self, &rhs);
+            }
+        }
+
+        impl std::ops::$assign_trait<f64> for Value {
+            fn $assign_method(&mut self, rhs: f64) {
+                *self = Value::$method(self, &Value::new(rhs));
+            }
+        }
+    };
+}
+
+impl_bin_op!(Add, add, AddAssign, add_assign);
+impl_bin_op!(Sub, sub, SubAssign, sub_assign);
+impl_bin_op!(Mul, mul, MulAssign, mul_assign);
+impl_bin_op!(Div, div, DivAssign, div_assign);
+
+impl std::ops::Neg for &Value {
+    type Output = Value;
+
+    fn neg(self) -> Value {
+        Value::neg(self)
+    }
+}
+
+impl std::ops::Neg for Value {
+    type Output = Value;
+
+    fn neg(self) -> Value {
+        -&self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gradcheck;
+    use crate::softmax;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    fn assert_unary_grad(f: impl Fn(&Value) -> Value, x: f64) {
+        gradcheck(&[x], |v| f(&v[0])).assert_ok();
+    }
 
     #[test]
     fn test_mul() {
         let a = Value::new(2.0);
-        let b = Value::new(2.0);
+        let b = Value::new(3.0);
         let c = a.mul(&b);
         c.backward();
 
-        println!("{:#?}", c);
+        assert_eq!(c.data(), 6.0);
+        assert_eq!(a.grad(), 3.0);
+        assert_eq!(b.grad(), 2.0);
+    }
+
+    #[test]
+    fn test_square_same_node() {
+        let x = Value::new(3.0);
+        let y = x.mul(&x);
+        y.backward();
+
+        assert_eq!(y.data(), 9.0);
+        assert_eq!(x.grad(), 6.0);
+    }
+
+    #[test]
+    fn test_diamond() {
+        // d = (2a) * (a + 1) = 2a^2 + 2a, dd/da = 4a + 2
+        let a = Value::new(3.0);
+        let b = a.mul(&Value::new(2.0));
+        let c = a.add(&Value::new(1.0));
+        let d = b.mul(&c);
+        d.backward();
+
+        assert_close(d.data(), 24.0);
+        assert_close(a.grad(), 14.0);
+        assert_close(b.grad(), 4.0);
+        assert_close(c.grad(), 6.0);
+    }
+
+    #[test]
+    fn test_shared_chain() {
+        // e = b * b + b where b = a * a, so e = a^4 + a^2, de/da = 4a^3 + 2a
+        let a = Value::new(1.5);
+        let b = a.mul(&a);
+        let e = b.mul(&b).add(&b);
+        e.backward();
+
+        assert_close(a.grad(), 4.0 * 1.5f64.powi(3) + 2.0 * 1.5);
+        assert_close(b.grad(), 2.0 * 2.25 + 1.0);
+    }
+
+    #[test]
+    fn test_softmax_grad() {
+        let logits = [0.5, -1.0, 2.0];
+        for (i, _) in logits.iter().enumerate() {
+            let x = logits.iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+            let p = softmax(&x);
+            p[i].backward();
+
+            let probs = p.iter().map(|v| v.data()).collect::<Vec<_>>();
+            for (j, x) in x.iter().enumerate() {
+                let delta = if i == j { 1.0 } else { 0.0 };
+                assert_close(x.grad(), probs[i] * (delta - probs[j]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_softmax_weighted_sum_grad() {
+        // L = sum_i w_i * p_i, dL/dx_j = p_j * (w_j - sum_i w_i p_i)
+        let w = [1.0, -2.0, 0.5, 3.0];
+        let x = [0.1, 0.2, -0.3, 1.2].iter().map(|&v| Value::new(v)).collect::<Vec<_>>();
+        let p = softmax(&x);
+        let loss = p.iter()
+            .zip(w.iter())
+            .map(|(p, &w)| p.mul(&Value::new(w)))
+            .sum::<Value>();
+        loss.backward();
+
+        let probs = p.iter().map(|v| v.data()).collect::<Vec<_>>();
+        let expected_mean = probs.iter().zip(w.iter()).map(|(p, w)| p * w).sum::<f64>();
+        for (j, x) in x.iter().enumerate() {
+            assert_close(x.grad(), probs[j] * (w[j] - expected_mean));
+        }
+    }
+
+    #[test]
+    fn test_log_is_natural() {
+        assert_close(Value::new(std::f64::consts::E).log().data(), 1.0);
+        assert_close(Value::new(8.0).log2().data(), 3.0);
+        assert_close(Value::new(1000.0).log10().data(), 3.0);
+        assert_close(Value::new(1e-10).log1p().data(), 1e-10);
+    }
+
+    #[test]
+    fn test_log_grads() {
+        for x in [0.1, 0.5, 1.0, 2.5, 10.0] {
+            assert_unary_grad(|v| v.log(), x);
+            assert_unary_grad(|v| v.log2(), x);
+            assert_unary_grad(|v| v.log10(), x);
+            assert_unary_grad(|v| v.log1p(), x);
+            assert_unary_grad(|v| v.exp(), x);
+        }
+    }
+
+    #[test]
+    fn test_operators() {
+        let a = Value::new(3.0);
+        let b = Value::new(4.0);
+
+        assert_eq!((&a + &b).data(), 7.0);
+        assert_eq!((&a - &b).data(), -1.0);
+        assert_eq!((&a * &b).data(), 12.0);
+        assert_eq!((&a / &b).data(), 0.75);
+        assert_eq!((-&a).data(), -3.0);
+        assert_eq!((&a * 2.0).data(), 6.0);
+        assert_eq!((2.0 - &a).data(), -1.0);
+        assert_eq!((1.0 / b.clone()).data(), 0.25);
+        assert_eq!((a.clone() + b.clone()).data(), 7.0);
+    }
+
+    #[test]
+    fn test_operator_grads() {
+        // y = (a * b + 1) / b - a = a + 1 / b - a, dy/da = 0, dy/db = -1 / b^2
+        let a = Value::new(3.0);
+        let b = Value::new(2.0);
+        let y = (&a * &b + 1.0) / &b - &a;
+        y.backward();
+
+        assert_close(y.data(), 0.5);
+        assert_close(a.grad(), 0.0);
+        assert_close(b.grad(), -0.25);
+    }
+
+    #[test]
+    fn test_assign_operators() {
+        let x = Value::new(2.0);
+        let mut acc = Value::new(1.0);
+        acc += &x;
+        acc *= &x;
+        acc -= 1.0;
+        acc /= &x;
+        acc.backward();
 
-        // assert_eq!(c.data, 4.0);
+        // acc = ((1 + x) * x - 1) / x = 1 + x - 1 / x
+        assert_close(acc.data(), 2.5);
+        assert_close(x.grad(), 1.0 + 1.0 / 4.0);
+
+        // `-=` makes a new node, `decrease_data` keeps it.
+        let p = Value::new(1.0);
+        let mut q = p.clone();
+        q -= 0.5;
+        p.decrease_data(0.25);
+        assert_close(p.data(), 0.75);
+        #[allow(deprecated)]
+        p.sub_assign(0.25);
+        assert_close(p.data(), 0.5);
+        assert_close(q.data(), 0.5);
+    }
+
+    #[test]
+    fn test_powi_zero() {
+        let x = Value::new(0.0);
+        let y = detect_anomaly(|| {
+            let y = x.powi(0);
+            y.backward();
+            y
+        });
+        assert_eq!(y.data(), 1.0);
+        assert_eq!(x.grad(), 0.0);
+
+        let x = Value::new(0.0);
+        let y = detect_anomaly(|| {
+            let y = x.powf(0.0);
+            y.backward();
+            y
+        });
+        assert_eq!(y.data(), 1.0);
+        assert_eq!(x.grad(), 0.0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_elementwise_grads() {
+        for x in [-2.0, -0.7, 0.3, 1.1, 2.5] {
+            assert_unary_grad(|v| v.powi(3), x);
+            assert_unary_grad(|v| v.tanh(), x);
+            assert_unary_grad(|v| v.sigmoid(), x);
+            assert_unary_grad(|v| v.abs(), x);
+            assert_unary_grad(|v| v.sin(), x);
+            assert_unary_grad(|v| v.cos(), x);
+            assert_unary_grad(|v| v.neg(), x);
+            assert_unary_grad(|v| v.square(), x);
+            assert_unary_grad(|v| v.reciprocal(), x);
+            assert_unary_grad(|v| v.softplus(), x);
+            assert_unary_grad(|v| v.clamp(-1.0, 1.0), x);
+            assert_unary_grad(|v| v.relu(), x);
+        }
+
+        for x in [0.2, 1.0, 3.7] {
+            assert_unary_grad(|v| v.powf(2.5), x);
+            assert_unary_grad(|v| v.powf(-0.5), x);
+            assert_unary_grad(|v| v.sqrt(), x);
+        }
+    }
+
+    #[test]
+    fn test_stable_activations() {
+        assert_close(Value::new(-800.0).sigmoid().data(), 0.0);
+        assert_close(Value::new(800.0).sigmoid().data(), 1.0);
+        assert_close(Value::new(800.0).softplus().data(), 800.0);
+        assert_close(Value::new(-800.0).softplus().data(), 0.0);
+        assert_close(Value::new(0.0).softplus().data(), std::f64::consts::LN_2);
+        assert_close(Value::new(5.0).clamp(-1.0, 1.0).data(), 1.0);
+    }
+
+    #[test]
+    fn test_binary_grads() {
+        let inputs = [1.3, -0.4];
+        gradcheck(&inputs, |x| x[0].mul(&x[1])).assert_ok();
+        gradcheck(&inputs, |x| x[0].div(&x[1])).assert_ok();
+        gradcheck(&inputs, |x| x[0].add(&x[1])).assert_ok();
+        gradcheck(&inputs, |x| x[0].sub(&x[1])).assert_ok();
+        gradcheck(&inputs, |x| x[0].max(&x[1])).assert_ok();
+        gradcheck(&inputs, |x| x[0].min(&x[1])).assert_ok();
+    }
+
+    fn anomaly_message(f: impl FnOnce() + std::panic::UnwindSafe) -> String {
+        let err = std::panic::catch_unwind(|| detect_anomaly(f)).unwrap_err();
+        err.downcast_ref::<String>().cloned().unwrap()
+    }
+
+    #[test]
+    fn test_detect_anomaly_forward() {
+        let msg = anomaly_message(|| {
+            let x = Value::new(3.0);
+            let y = &x - 3.0;
+            y.log();
+        });
+        assert!(msg.starts_with("anomaly detected in forward: Log output is -inf for operands [0.0]"), "{}", msg);
+        assert!(msg.contains("Sub = 0"), "{}", msg);
+        assert!(msg.contains("leaf = 3"), "{}", msg);
+        assert!(!is_detect_anomaly());
+
+        let msg = anomaly_message(|| {
+            Value::new(1.0).div(&Value::new(0.0));
+        });
+        assert!(msg.contains("Div output is inf for operands [1.0, 0.0]"), "{}", msg);
+
+        // Off by default, the NaN just propagates.
+        assert!(Value::new(-1.0).sqrt().data().is_nan());
+    }
+
+    #[test]
+    fn test_detect_anomaly_backward() {
+        let msg = anomaly_message(|| {
+            let x = Value::new(0.0);
+            let y = x.sqrt() * 2.0;
+            y.backward();
+        });
+        assert!(msg.starts_with("anomaly detected in backward: Sqrt grad is inf for operands [0.0] and output grad 2"), "{}", msg);
+
+        detect_anomaly(|| {
+            let x = Value::new(2.0);
+            let y = x.log() * x.sqrt();
+            y.backward();
+            assert!(x.grad().is_finite());
+        });
+    }
+}
@@ -1,28 +1,45 @@
-use std::collections::HashMap;
-use std::iter::zip;
 use dataset::Mnist;
+use microml::Average;
+use microml::ConfusionMatrix;
 use microml::MLP;
-use microml::Value;
-use microml::calculate_accuracy;
-use microml::cross_entropy_loss;
-use microml::get_predicted_label;
-use microml::one_hot_encode;
-use microml::softmax;
+use microml::LrScheduler;
+use microml::Module;
+use microml::Optimizer;
+use microml::Persist;
+use microml::Sgd;
+use microml::StepLr;
+use microml::Tape;
+use microml::top_k_accuracy;
 use simple_logger::SimpleLogger;
 
+const IMAGE_SIZE: usize = 28 * 28;
+const NUM_CLASSES: usize = 10;
+const SEED: u64 = 42;
+const MODEL_PATH: &str = "mnist_model.bin";
+
 #[tokio::main]
 async fn main() {
     SimpleLogger::new().with_level(log::LevelFilter::Info).init().unwrap();
+    microml::seed(SEED);
+    log::info!("seed: {}", SEED);
 
     let mnist = Mnist::load().await.unwrap();
 
-    let mlp = MLP::new(&[784, 128, 64, 10]);
+    let mlp = match MLP::load(MODEL_PATH) {
+        Ok(mlp) => {
+            log::info!("loaded model from {}", MODEL_PATH);
+            mlp
+        },
+        Err(e) => {
+            log::info!("starting from scratch, could not load {}: {}", MODEL_PATH, e);
+            MLP::new(&[784, 128, 64, 10])
+        },
+    };
     log::info!("parameter count: {}", mlp.parameters().len());
 
-    let learning_rate = 0.001;
+    let learning_rate = 0.01;
     let batch_size = 64;
     let num_batches = mnist.train_images.count() / batch_size;
-    let mut label_losses: HashMap<u8, f64> = HashMap::new();
     let mut last_loss = 0.0;
     let mut last_accuracy = 0.0;
 
@@ -31,47 +48,38 @@ async fn main() {
     log::info!("batch_size: {}", batch_size);
     log::info!("num_batches: {}", num_batches);
 
-    let mut i = 0;
+    let mut optimizer = Sgd::new(mlp.parameters(), learning_rate).momentum(0.9);
+    let mut scheduler = StepLr::new(learning_rate, 2, 0.5);
+    let mut tape = Tape::new();
 
     for epoch in 0..5 {
-        let mut predicted_labels: Vec<u32> = vec![];
-        let mut actual_labels: Vec<u32> = vec![];
+        let mut window = ConfusionMatrix::new(NUM_CLASSES);
+        let mut epoch_matrix = ConfusionMatrix::new(NUM_CLASSES);
+        let mut top5_hits = 0.0;
 
         for batch in 0..num_batches - 1 {
-            let inputs = mnist.train_images.get_batch(batch, batch_size).unwrap()    
-                .chunks(batch_size as usize);
-            let labels = mnist.train_labels.get_batch(batch, batch_size).unwrap();
-
-            let mut batch_loss = 0.0;
-            for (input, label) in zip(inputs, labels) {
-                actual_labels.push(*label as u32);
-                let input = input.iter().map(|p| Value::new(*p as f64 / 255.0)).collect::<Vec<Value>>();
-                let out = mlp.forward(input);
-                let out =  softmax(&out);
-                let predicted_label = get_predicted_label(&out);
-                predicted_labels.push(predicted_label as u32);
-                let label = one_hot_encode(*label as usize, 10);
-                let loss = cross_entropy_loss(&out, &label);
-                batch_loss += loss.data();
-                loss.backward();
-
-                if i % 10_000 == 0 { 
-                    let out = out.iter().map(|v| v.data()).collect::<Vec<f64>>();
-                    let label = label.iter().map(|v| v.data()).collect::<Vec<f64>>();
-                    log::info!("out: {:.4?} label: {:.4?}", out, label);
-                }
-
-                i += 1;
-            }
-
-            batch_loss /= batch_size as f64;
-            
-            for p in mlp.parameters() {
-                let grad = p.grad() / batch_size as f64;
-                p.sub_assign(grad * learning_rate);
-            }
-
-            mlp.zero_grad();
+            let pixels = mnist.train_images.get_batch(batch, batch_size).unwrap()
+                .iter()
+                .map(|p| *p as f64 / 255.0)
+                .collect::<Vec<f64>>();
+            let labels = mnist.train_labels.get_batch(batch, batch_size).unwrap()
+                .iter()
+                .map(|l| *l as usize)
+                .collect::<Vec<usize>>();
+
+            tape.clear();
+            let input = tape.leaf(pixels, labels.len(), IMAGE_SIZE);
+            let out = mlp.forward_tape(&mut tape, input);
+            let loss = tape.cross_entropy(out, &labels);
+            tape.backward(loss);
+            let batch_loss = tape.value(loss)[0];
+
+            window.update(&labels, &tape.argmax(out));
+            let scores = tape.value(out).chunks(NUM_CLASSES).map(|s| s.to_vec()).collect::<Vec<_>>();
+            top5_hits += top_k_accuracy(&scores, &labels, 5) * labels.len() as f64;
+
+            optimizer.step();
+            optimizer.zero_grad();
 
             if batch % 50 == 0 {
                 let loss_str = if batch_loss > last_loss {
@@ -81,7 +89,7 @@ async fn main() {
                 };
                 last_loss = batch_loss;
 
-                let accuracy = calculate_accuracy(&actual_labels, &predicted_labels);
+                let accuracy = window.accuracy();
 
                 let acc_str = if last_accuracy > accuracy {
                     "↓"
@@ -90,11 +98,20 @@ async fn main() {
                 };
                 last_accuracy = accuracy;
 
-                log::info!("epoch: {}, batch: {}, loss: {} {} accuracy: {} {}", epoch, batch, batch_loss, loss_str, accuracy, acc_str);
+                log::info!("epoch: {}, batch: {}, loss: {} {} accuracy: {} {} macro f1: {:.4}", epoch, batch, batch_loss, loss_str, accuracy, acc_str, window.f1_avg(Average::Macro));
 
-                actual_labels.clear();
-                predicted_labels.clear();
+                epoch_matrix.merge(&window);
+                window.reset();
             }
         }
+
+        epoch_matrix.merge(&window);
+        let top5 = top5_hits / epoch_matrix.total() as f64;
+
+        scheduler.step(&mut optimizer);
+        log::info!("epoch: {} done, learning_rate: {}, top-5 accuracy: {:.4}\n{}", epoch, optimizer.learning_rate(), top5, epoch_matrix);
     }
+
+    mlp.save(MODEL_PATH).unwrap();
+    log::info!("saved model to {}", MODEL_PATH);
 }
\ No newline at end of file
@@ -0,0 +1,77 @@
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+pub struct Moons {
+    pub points: Vec<Point>,
+    pub labels: Vec<i32>,
+}
+
+/// Two interleaving half circles like scikit-learn's `make_moons`, shuffled
+/// and with gaussian noise, drawn from `rng` so a seeded run is reproducible.
+pub fn generate_moons<R: Rng>(n: usize, noise: f64, rng: &mut R) -> Moons {
+    let n_outer = n / 2;
+    let n_inner = n - n_outer;
+    let angle = |i: usize, count: usize| std::f64::consts::PI * i as f64 / (count.max(2) - 1) as f64;
+
+    let mut samples = (0..n_outer)
+        .map(|i| (angle(i, n_outer).cos(), angle(i, n_outer).sin(), 0))
+        .chain((0..n_inner).map(|i| (1.0 - angle(i, n_inner).cos(), 0.5 - angle(i, n_inner).sin(), 1)))
+        .collect::<Vec<_>>();
+    samples.shuffle(rng);
+
+    // Box-Muller, 1 - u keeps the logarithm away from zero.
+    let mut gaussian = || {
+        let u1: f64 = 1.0 - rng.gen::<f64>();
+        let u2: f64 = rng.gen();
+        noise * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    };
+    let points = samples.iter()
+        .map(|(x, y, _)| Point { x: x + gaussian(), y: y + gaussian() })
+        .collect();
+
+    Moons {
+        points,
+        labels: samples.iter().map(|(_, _, label)| *label).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    #[test]
+    fn test_moons_without_noise() {
+        let moons = generate_moons(101, 0.0, &mut StdRng::seed_from_u64(0));
+        assert_eq!(moons.points.len(), 101);
+        assert_eq!(moons.labels.iter().filter(|&&l| l == 0).count(), 50);
+        assert_eq!(moons.labels.iter().filter(|&&l| l == 1).count(), 51);
+
+        // The outer moon is the upper unit half circle, the inner one the
+        // same circle flipped and moved to (1, 0.5).
+        for (p, &label) in moons.points.iter().zip(moons.labels.iter()) {
+            let (cx, cy, sign) = if label == 0 { (0.0, 0.0, 1.0) } else { (1.0, 0.5, -1.0) };
+            assert!(((p.x - cx).powi(2) + (p.y - cy).powi(2) - 1.0).abs() < 1e-12);
+            assert!(sign * (p.y - cy) >= -1e-12);
+        }
+    }
+
+    #[test]
+    fn test_moons_are_seeded() {
+        let a = generate_moons(50, 0.1, &mut StdRng::seed_from_u64(7));
+        let b = generate_moons(50, 0.1, &mut StdRng::seed_from_u64(7));
+        let c = generate_moons(50, 0.1, &mut StdRng::seed_from_u64(8));
+        let coords = |m: &Moons| m.points.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>();
+
+        assert_eq!(coords(&a), coords(&b));
+        assert_eq!(a.labels, b.labels);
+        assert_ne!(coords(&a), coords(&c));
+    }
+}
@@ -1,19 +1,27 @@
-use std::collections::HashMap;
+mod data;
+
 use std::iter::zip;
 
-use dataset::Point;
-use dataset::generate_moons;
+use microml::Activation;
 use microml::MLP;
+use microml::Module;
+use microml::Optimizer;
+use microml::PlateauMode;
+use microml::ReduceLrOnPlateau;
+use microml::Sgd;
 use microml::Value;
+use microml::batch_cross_entropy_with_logits;
 use microml::calculate_accuracy;
-use microml::cross_entropy_loss;
 use microml::get_predicted_label;
-use microml::one_hot_encode;
 use microml::softmax;
 use plotters::prelude::*;
 use simple_logger::SimpleLogger;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+
+use crate::data::Point;
+use crate::data::generate_moons;
+
+const SEED: u64 = 42;
 
 fn plot_moons(data: &[Point], labels: &[i32], image_name: &str) -> anyhow::Result<()> {
     let root = BitMapBackend::new(image_name, (640, 480)).into_drawing_area();
@@ -48,19 +56,31 @@ fn plot_moons(data: &[Point], labels: &[i32], image_name: &str) -> anyhow::Resul
 #[tokio::main]
 async fn main() {
     SimpleLogger::new().with_level(log::LevelFilter::Info).init().unwrap();
+    microml::seed(SEED);
+    log::info!("seed: {}", SEED);
     
-    let train_dataset = generate_moons(3000, 0.1);
+    let train_dataset = microml::with_rng(|rng| generate_moons(3000, 0.1, rng));
     log::info!("train_dataset: {:?}", train_dataset.labels.iter().take(10).collect::<Vec<_>>());
-    let test_dataset = generate_moons(100, 0.01);
+    let test_dataset = microml::with_rng(|rng| generate_moons(100, 0.01, rng));
     plot_moons(&train_dataset.points, &train_dataset.labels, "mooons_train_set.png").unwrap();
 
-    let learning_rate = 0.000001;
+    let learning_rate = 0.05;
     let lambda = 0.00001;
     let batch_size = 32; // Set your batch size
 
-    let mut rng = thread_rng();
-
-    let mlp = MLP::new(&[2, 50, 2]);
+    // Swap the hidden activation to compare how they do on the moons.
+    let hidden_activation = Activation::Relu;
+    let mlp = MLP::builder(2)
+        .layer(50, hidden_activation)
+        .layer(2, Activation::Identity)
+        .build();
+    log::info!("hidden_activation: {:?}", hidden_activation);
+    let mut optimizer = Sgd::new(mlp.parameters(), learning_rate)
+        .momentum(0.9)
+        .weight_decay(lambda);
+    let mut scheduler = ReduceLrOnPlateau::new(learning_rate, PlateauMode::Min)
+        .patience(1)
+        .factor(0.5);
 
     let mut real_labels = Vec::new();
     let mut predicted_labels = Vec::new();
@@ -68,75 +88,63 @@ async fn main() {
     let mut i = 0;
 
     for epoch in 0..7 {
-        train_bathes.shuffle(&mut rng);
+        microml::with_rng(|rng| train_bathes.shuffle(rng));
 
         let mut total_loss = 0.0;
-        let mut batch_count = 0;
+        let mut sample_count = 0;
 
         for batch in train_bathes.chunks(batch_size) {
-            let mut batch_loss = 0.0;
-            let mut total_grad = 0.0;
+            let inputs = batch.iter()
+                .map(|(point, _)| vec![Value::new(point.x), Value::new(point.y)])
+                .collect::<Vec<_>>();
+            let targets = batch.iter().map(|(_, label)| *label as usize).collect::<Vec<_>>();
+            let outs = mlp.forward_batch(&inputs);
 
-            for (point, label) in batch {
+            for (out, label) in zip(&outs, &targets) {
                 real_labels.push(*label as u32);
-                let input = vec![point.x, point.y];
-                let out = mlp.forward(input.iter().map(|p| Value::new(*p)).collect::<Vec<Value>>());
-                let out = softmax(&out);
-                let label_hot = one_hot_encode(*label as usize, 2);
-                let predicted_label = get_predicted_label(&out);
-                predicted_labels.push(predicted_label as u32);
-
-                let loss = cross_entropy_loss(&out, &label_hot);
-                batch_loss += loss.data();
-                loss.backward();
+                predicted_labels.push(get_predicted_label(out) as u32);
 
                 if i % 5_111 == 0 { 
-                    let out = out.iter().map(|v| v.data()).collect::<Vec<f64>>();
-                    let label_hot = label_hot.iter().map(|v| v.data()).collect::<Vec<f64>>();
-                    log::info!("loss: {} out: {:.4?} label: {:.4?} hot_label: {:.4?}", loss.data(), out, label, label_hot);
+                    let out = softmax(out).iter().map(|v| v.data()).collect::<Vec<f64>>();
+                    log::info!("out: {:.4?} label: {:.4?}", out, label);
                 }
 
                 i += 1;
-
-                for p in mlp.parameters() {
-                    total_grad += p.grad();
-                }
-
-                mlp.zero_grad();
             }
 
-            let avg_grad = total_grad / batch_size as f64 * learning_rate;
+            let loss = batch_cross_entropy_with_logits(&outs, &targets);
+            loss.backward();
 
-            for p in mlp.parameters() {
-                //let l2_penalty = lambda * p.data(); // L2 penalty term
-                p.sub_assign(avg_grad);
-            }
+            optimizer.step();
+            optimizer.zero_grad();
 
-            total_loss += batch_loss;
-            batch_count += 1;
+            // The batch loss is a mean, weight it back by the batch size so
+            // the short last batch does not skew the epoch average.
+            total_loss += loss.data() * batch.len() as f64;
+            sample_count += batch.len();
         }
 
-        let average_loss = total_loss / batch_count as f64;
+        let average_loss = total_loss / sample_count as f64;
         let accuracy = calculate_accuracy(&real_labels, &predicted_labels);
         real_labels.clear();
         predicted_labels.clear();
 
-        log::info!("epoch: {} average_loss: {:.4?} accuracy: {:.2?}", epoch, average_loss, accuracy);
+        scheduler.step(average_loss, &mut optimizer);
+
+        log::info!("epoch: {} average_loss: {:.4?} accuracy: {:.2?} learning_rate: {}", epoch, average_loss, accuracy, optimizer.learning_rate());
     }
 
     let mut predictions = vec![];
 
     for (point, label) in zip(&test_dataset.points, test_dataset.labels) {
-        let input = vec![point.x, point.y];
-        let out = mlp.forward(input.iter().map(|p| Value::new(*p)).collect::<Vec<Value>>());
-        let out = softmax(&out);
-        let predicted_label = get_predicted_label(&out);
+        // No gradients needed here, so skip building the graph.
+        let out = mlp.forward_f64(&[point.x, point.y]);
+        let predicted_label = (0..out.len()).max_by(|&a, &b| out[a].total_cmp(&out[b])).unwrap();
         predictions.push(predicted_label as i32);
-        let label_hot = one_hot_encode(label as usize, 2);
-        let loss = cross_entropy_loss(&out, &label_hot);
-        let label_hot = label_hot.iter().map(|v| v.data()).collect::<Vec<f64>>();
-        let out = out.iter().map(|v| v.data()).collect::<Vec<f64>>();
-        log::info!("test loss: {} out: {:.4?} label: {:.4?} hot_label: {:.4?} predicted_label: {:?}", loss.data(), out, label, label_hot, predicted_label);
+
+        let max = out[predicted_label];
+        let loss = max + out.iter().map(|x| (x - max).exp()).sum::<f64>().ln() - out[label as usize];
+        log::info!("test loss: {} logits: {:.4?} label: {:.4?} predicted_label: {:?}", loss, out, label, predicted_label);
     }
 
     plot_moons(&test_dataset.points, &predictions, "moons_predictions.png").unwrap();